@@ -21,8 +21,19 @@ slap_expr_t slap_expr_new(slap_context_t, ssize_t *coeffs, size_t n,
 
 slap_graph_t slap_graph_new_start(slap_context_t, slap_graph_t next);
 slap_graph_t slap_graph_new_end(slap_context_t);
+typedef enum : int {
+  SLAP_ACCESS_READ,
+  SLAP_ACCESS_WRITE,
+  SLAP_ACCESS_READ_WRITE,
+} slap_access_kind;
+
 slap_graph_t slap_graph_new_access(slap_context_t, size_t memref,
-                                   slap_expr_t offset, slap_graph_t next);
+                                   slap_expr_t offset, slap_access_kind kind,
+                                   slap_graph_t next);
+slap_graph_t slap_graph_new_access_nd(slap_context_t, size_t memref,
+                                      slap_expr_t *subscripts,
+                                      const ssize_t *strides, size_t rank,
+                                      slap_access_kind kind, slap_graph_t next);
 slap_graph_t slap_graph_new_update(slap_context_t, size_t ivar,
                                    slap_expr_t expr, slap_graph_t next);
 slap_graph_t slap_graph_new_branch(slap_context_t, size_t ivar,
@@ -54,13 +65,79 @@ ssize_t *slap_expr_get_coefficients(slap_expr_t);
 size_t slap_expr_get_length(slap_expr_t);
 ssize_t slap_expr_get_bias(slap_expr_t);
 
+typedef enum : int {
+  SLAP_QUASI_FLOOR_DIV,
+  SLAP_QUASI_MOD,
+} slap_quasi_op_t;
+
+// Terms `coefficient * (operand floordiv divisor)` or
+// `coefficient * (operand mod divisor)` added to the affine part. Division
+// rounds towards negative infinity and the divisor is positive.
+typedef struct {
+  ssize_t coefficient;
+  slap_quasi_op_t op;
+  slap_expr_t operand;
+  ssize_t divisor;
+} slap_quasi_term_t;
+
+// Like slap_expr_new with `terms` added. Every operand must be non-null;
+// returns null if a divisor is not positive.
+slap_expr_t slap_expr_new_quasi(slap_context_t, ssize_t *coeffs, size_t n,
+                                ssize_t bias, const slap_quasi_term_t *terms,
+                                size_t term_count);
+size_t slap_expr_get_term_count(slap_expr_t);
+ssize_t slap_expr_get_term_coefficient(slap_expr_t, size_t);
+slap_quasi_op_t slap_expr_get_term_op(slap_expr_t, size_t);
+ssize_t slap_expr_get_term_divisor(slap_expr_t, size_t);
+slap_expr_t slap_expr_get_term_operand(slap_expr_t, size_t);
+
+typedef enum : int {
+  SLAP_EXTRACT_OK,
+  SLAP_EXTRACT_FILE_NOT_FOUND,
+  SLAP_EXTRACT_PARSE_ERROR,
+  SLAP_EXTRACT_NO_AFFINE_LOOP,
+  SLAP_EXTRACT_UNSUPPORTED_EXPR,
+} slap_extract_error;
+
 slap_graph_t slap_extract_affine_loop(slap_context_t, char *path, size_t length,
-                                      size_t **vaddr, size_t *vaddr_len);
+                                      size_t **vaddr, size_t *vaddr_len,
+                                      slap_extract_error *error);
+
+// Returned by slap_sim_get_node_id and slap_sim_get_memref_vaddr for a null
+// or unknown node and an out-of-range memref. Accesses with an invalid node
+// id are ignored, and slap_sim_access_address returns false for them.
+#define SLAP_SIM_INVALID ((size_t)-1)
 
 void slap_sim_access(slap_sim_context_t, size_t node_id, size_t block_id);
+void slap_sim_access_weighted(slap_sim_context_t, size_t node_id,
+                              size_t block_id, size_t weight);
+bool slap_sim_access_address(slap_sim_context_t, size_t node_id,
+                             size_t address);
 size_t slap_sim_get_memref_vaddr(slap_sim_context_t, size_t memref_id);
 size_t slap_sim_get_node_id(slap_sim_context_t, slap_graph_t graph);
 size_t slap_sim_get_block_size(slap_sim_context_t);
+// Addresses passed to slap_sim_access_address are taken relative to this
+// base: the block id is (address - base) rounded down to its block size.
+size_t slap_sim_get_base_addr(slap_sim_context_t);
+size_t slap_sim_get_memref_block_size(slap_sim_context_t, size_t memref_id);
+// The limit of with_max_logic_time, or SLAP_SIM_INVALID if there is none. It
+// also bounds the branches run in a row without an access; a simulation that
+// exceeds it calls slap_sim_report_timeout and returns.
+size_t slap_sim_get_max_logic_time(slap_sim_context_t);
+void slap_sim_report_timeout(slap_sim_context_t);
+
+// Read back the reuse histogram of a node once the simulation has finished.
+// Unknown node ids and intervals read as empty. slap_sim_node_interval_iter
+// returns the number of distinct intervals. slap_sim_node_histogram_copy
+// stores up to capacity of the smallest intervals and their counts into the
+// two buffers in one pass, in increasing order, and returns the number of
+// distinct intervals, which may exceed capacity.
+size_t slap_sim_get_node_interval_count(slap_sim_context_t, size_t node_id,
+                                        size_t interval);
+size_t slap_sim_node_interval_iter(slap_sim_context_t, size_t node_id);
+size_t slap_sim_node_histogram_copy(slap_sim_context_t, size_t node_id,
+                                    size_t *intervals, size_t *counts,
+                                    size_t capacity);
 
 #ifdef __cplusplus
 }
@@ -1,18 +1,160 @@
-use std::cell::UnsafeCell;
+use std::{cell::UnsafeCell, ops::RangeInclusive};
 
 use crate::Context;
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EvalError {
+    /// A non-zero coefficient refers to an induction variable with no value.
+    UnboundIvar(usize),
+    Overflow,
+}
+
+impl std::fmt::Display for EvalError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EvalError::UnboundIvar(ivar) => write!(f, "induction variable {ivar} is unbound"),
+            EvalError::Overflow => write!(f, "affine expression overflowed"),
+        }
+    }
+}
+
+impl std::error::Error for EvalError {}
+
+/// Operation of a [`QuasiTerm`].
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum QuasiOp {
+    FloorDiv,
+    Mod,
+}
+
+impl QuasiOp {
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            QuasiOp::FloorDiv => "floordiv",
+            QuasiOp::Mod => "mod",
+        }
+    }
+}
+
+/// Non-affine term `coefficient * (operand floordiv divisor)` or
+/// `coefficient * (operand mod divisor)` of an [`Expr`], as introduced by
+/// tiling. The divisor is a positive constant, as in the affine dialect.
+#[repr(C)]
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct QuasiTerm<'a> {
+    pub coefficient: isize,
+    pub op: QuasiOp,
+    pub operand: &'a Expr<'a>,
+    pub divisor: isize,
+}
+
+impl QuasiTerm<'_> {
+    fn eval(&self, ivars: &[isize]) -> Result<isize, EvalError> {
+        let value = self.operand.eval(ivars)?;
+        match self.op {
+            QuasiOp::FloorDiv => value.checked_div_euclid(self.divisor),
+            QuasiOp::Mod => value.checked_rem_euclid(self.divisor),
+        }
+        .and_then(|x| x.checked_mul(self.coefficient))
+        .ok_or(EvalError::Overflow)
+    }
+    fn range(&self, ivars: &[RangeInclusive<isize>]) -> (i128, i128) {
+        let operand = self.operand.range(ivars);
+        let (lo, hi) = (*operand.start(), *operand.end());
+        let (lo, hi) = match self.op {
+            QuasiOp::FloorDiv => (lo.div_euclid(self.divisor), hi.div_euclid(self.divisor)),
+            // The remainders only stay in order if no multiple of the divisor
+            // lies in between.
+            QuasiOp::Mod if lo.div_euclid(self.divisor) == hi.div_euclid(self.divisor) => {
+                (lo.rem_euclid(self.divisor), hi.rem_euclid(self.divisor))
+            }
+            QuasiOp::Mod => (0, self.divisor - 1),
+        };
+        scale((lo, hi), self.coefficient)
+    }
+}
+
+/// `range * coefficient`, which cannot overflow `i128`.
+fn scale((lo, hi): (isize, isize), coefficient: isize) -> (i128, i128) {
+    let (lo, hi) = (
+        lo as i128 * coefficient as i128,
+        hi as i128 * coefficient as i128,
+    );
+    (lo.min(hi), lo.max(hi))
+}
+
+/// `sum(coefficients[i] * i) + bias`, plus any floordiv/mod terms. Affine
+/// analyses only look at the coefficients and bias, so they must check
+/// [`Expr::is_affine`] and treat other expressions as opaque.
 #[derive(Debug, Clone)]
 pub struct Expr<'a> {
     coefficent: &'a [isize],
     bias: isize,
+    terms: &'a [QuasiTerm<'a>],
+}
+
+/// Expressions are compared structurally, ignoring trailing zero
+/// coefficients. Other rewrites that keep `eval`, such as reordering terms,
+/// only compare equal after [`Expr::simplify`].
+impl PartialEq for Expr<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        self.bias == other.bias && self.trimmed() == other.trimmed() && self.terms == other.terms
+    }
+}
+
+impl Eq for Expr<'_> {}
+
+/// Order consistent with `PartialEq`, used to sort terms canonically.
+impl Ord for Expr<'_> {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.trimmed()
+            .cmp(other.trimmed())
+            .then(self.bias.cmp(&other.bias))
+            .then_with(|| self.terms.cmp(other.terms))
+    }
+}
+
+impl PartialOrd for Expr<'_> {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl std::hash::Hash for Expr<'_> {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.trimmed().hash(state);
+        self.bias.hash(state);
+        self.terms.hash(state);
+    }
 }
 
 impl<'a> Expr<'a> {
     pub fn new(ctx: &'a Context, coefficent: &[isize], bias: isize) -> Self {
         let coefficent = ctx.arena.alloc_slice_copy(coefficent);
-        Self { coefficent, bias }
+        Self {
+            coefficent,
+            bias,
+            terms: &[],
+        }
     }
+    /// Quasi-affine expression: the affine part plus `terms`. `None` if a
+    /// divisor is not positive.
+    pub fn with_terms(
+        ctx: &'a Context,
+        coefficent: &[isize],
+        bias: isize,
+        terms: &[QuasiTerm<'a>],
+    ) -> Option<Self> {
+        if terms.iter().any(|x| x.divisor <= 0) {
+            return None;
+        }
+        Some(Self {
+            terms: ctx.arena.alloc_slice_clone(terms),
+            ..Self::new(ctx, coefficent, bias)
+        })
+    }
+    /// Feature vector of the affine part; floordiv/mod terms are left out.
     pub fn vectorize_into(&self, target: &mut Vec<isize>) {
         target.extend_from_slice(self.coefficent);
         target.push(self.bias);
@@ -20,6 +162,379 @@ impl<'a> Expr<'a> {
     pub fn affine_dim(&self) -> usize {
         self.coefficent.len() + 1
     }
+    /// Coefficients of the affine part.
+    pub fn coefficients(&self) -> &'a [isize] {
+        self.coefficent
+    }
+    pub fn bias(&self) -> isize {
+        self.bias
+    }
+    pub fn terms(&self) -> &'a [QuasiTerm<'a>] {
+        self.terms
+    }
+    /// Whether the expression has no floordiv/mod terms.
+    pub fn is_affine(&self) -> bool {
+        self.terms.is_empty()
+    }
+    /// The value of an expression that uses no induction variable.
+    pub fn constant(&self) -> Option<isize> {
+        (self.is_affine() && self.coefficent.iter().all(|x| *x == 0)).then_some(self.bias)
+    }
+    /// One past the largest induction variable the expression has a
+    /// coefficient for, including inside its terms.
+    pub fn ivar_count(&self) -> usize {
+        self.terms
+            .iter()
+            .map(|x| x.operand.ivar_count())
+            .fold(self.coefficent.len(), usize::max)
+    }
+    /// Copy of the expression, terms included, allocated in `ctx`.
+    pub fn clone_into<'b>(&self, ctx: &'b Context) -> &'b Expr<'b> {
+        let terms = self
+            .terms
+            .iter()
+            .map(|x| QuasiTerm {
+                operand: x.operand.clone_into(ctx),
+                ..*x
+            })
+            .collect::<Vec<_>>();
+        ctx.arena.alloc(Expr {
+            terms: ctx.arena.alloc_slice_clone(&terms),
+            ..Expr::new(ctx, self.coefficent, self.bias)
+        })
+    }
+    /// Canonical form of the expression: trailing zero coefficients are
+    /// dropped, operands are simplified, terms on a constant operand are
+    /// folded into the bias, terms on the same operation, operand and divisor
+    /// are merged, terms with a zero coefficient are dropped and the rest are
+    /// sorted. `eval` is unchanged, except that a folded term which would
+    /// overflow is kept, and simplifying twice yields the same expression.
+    pub fn simplify(&self, ctx: &'a Context) -> &'a Expr<'a> {
+        let mut bias = self.bias;
+        let mut terms = Vec::<QuasiTerm<'a>>::new();
+        for term in self.terms {
+            let term = QuasiTerm {
+                operand: term.operand.simplify(ctx),
+                ..term.clone()
+            };
+            if term.operand.constant().is_some() {
+                if let Some(x) = term.eval(&[]).ok().and_then(|x| bias.checked_add(x)) {
+                    bias = x;
+                    continue;
+                }
+            }
+            let same = terms
+                .iter_mut()
+                .find(|x| (x.op, x.operand, x.divisor) == (term.op, term.operand, term.divisor));
+            match same.map(|x| (x.coefficient.checked_add(term.coefficient), x)) {
+                Some((Some(sum), x)) => x.coefficient = sum,
+                _ => terms.push(term),
+            }
+        }
+        terms.retain(|x| x.coefficient != 0);
+        terms.sort();
+        ctx.arena.alloc(Expr {
+            coefficent: self.trimmed(),
+            bias,
+            terms: ctx.arena.alloc_slice_clone(&terms),
+        })
+    }
+    fn trimmed(&self) -> &'a [isize] {
+        let len = self
+            .coefficent
+            .iter()
+            .rposition(|x| *x != 0)
+            .map_or(0, |x| x + 1);
+        &self.coefficent[..len]
+    }
+    /// Smallest range containing the value of the expression whenever each
+    /// induction variable `i` lies in `ivars[i]`. Variables past the end of
+    /// `ivars` may take any value. Values that would overflow `isize` make
+    /// `eval` fail, so the range is clamped to `isize`.
+    pub fn range(&self, ivars: &[RangeInclusive<isize>]) -> RangeInclusive<isize> {
+        let mut terms = self
+            .coefficent
+            .iter()
+            .enumerate()
+            .filter(|(_, coeff)| **coeff != 0)
+            .map(|(ivar, coeff)| {
+                let range = ivars.get(ivar).cloned().unwrap_or(isize::MIN..=isize::MAX);
+                scale(range.into_inner(), *coeff)
+            })
+            .chain(self.terms.iter().map(|x| x.range(ivars)));
+        let bias = self.bias as i128;
+        let sum = terms.try_fold((bias, bias), |(lo, hi), (x, y)| {
+            Some((lo.checked_add(x)?, hi.checked_add(y)?))
+        });
+        let clamp = |x: i128| x.clamp(isize::MIN as i128, isize::MAX as i128) as isize;
+        match sum {
+            Some((lo, hi)) => clamp(lo)..=clamp(hi),
+            None => isize::MIN..=isize::MAX,
+        }
+    }
+    /// Evaluate the expression with `ivars[i]` bound to induction variable
+    /// `i`. Only non-zero coefficients need a binding.
+    ///
+    /// `floordiv` rounds towards negative infinity and `mod` is the matching
+    /// remainder in `0..divisor`, as in the affine dialect: `-7 floordiv 2`
+    /// is `-4` and `-7 mod 2` is `1`. Truncating division, as Rust's `/` and
+    /// `%` do, would give `-3` and `-1` and break the tiles of negative
+    /// indices.
+    pub fn eval(&self, ivars: &[isize]) -> Result<isize, EvalError> {
+        let affine = self
+            .coefficent
+            .iter()
+            .enumerate()
+            .filter(|(_, coeff)| **coeff != 0)
+            .try_fold(self.bias, |acc, (ivar, coeff)| {
+                let value = ivars.get(ivar).ok_or(EvalError::UnboundIvar(ivar))?;
+                coeff
+                    .checked_mul(*value)
+                    .and_then(|x| acc.checked_add(x))
+                    .ok_or(EvalError::Overflow)
+            })?;
+        self.terms.iter().try_fold(affine, |acc, term| {
+            acc.checked_add(term.eval(ivars)?)
+                .ok_or(EvalError::Overflow)
+        })
+    }
+}
+
+/// Conventional affine notation, e.g. `2*i0 - i1 + 3`, where `iN` is the
+/// `N`-th induction variable. Terms are written as calls, e.g.
+/// `floordiv(i0 + 1, 4)` or `2*mod(i1, 8)`.
+impl std::fmt::Display for Expr<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let terms = self
+            .terms
+            .iter()
+            .map(|x| {
+                (
+                    x.coefficient,
+                    x.op,
+                    x.operand as &dyn std::fmt::Display,
+                    x.divisor,
+                )
+            })
+            .collect::<Vec<_>>();
+        write_affine(f, self.coefficent, self.bias, &terms)
+    }
+}
+
+/// `(coefficient, op, operand, divisor)` of a term in the text notation.
+pub(crate) type TermParts<T> = (isize, QuasiOp, T, isize);
+pub(crate) type TermText<'a> = TermParts<&'a dyn std::fmt::Display>;
+
+pub(crate) fn write_affine(
+    f: &mut std::fmt::Formatter<'_>,
+    coefficients: &[isize],
+    bias: isize,
+    quasi: &[TermText],
+) -> std::fmt::Result {
+    enum Term<'t, 'a> {
+        Ivar(usize),
+        Quasi(&'t TermText<'a>),
+        Bias,
+    }
+    let terms = coefficients
+        .iter()
+        .enumerate()
+        .filter(|(_, coeff)| **coeff != 0)
+        .map(|(ivar, coeff)| (*coeff, Term::Ivar(ivar)))
+        .chain(
+            quasi
+                .iter()
+                .filter(|x| x.0 != 0)
+                .map(|x| (x.0, Term::Quasi(x))),
+        )
+        .chain((bias != 0).then_some((bias, Term::Bias)));
+    let mut empty = true;
+    for (value, term) in terms {
+        let magnitude = value.unsigned_abs();
+        match (empty, value < 0) {
+            (true, true) => write!(f, "-")?,
+            (true, false) => (),
+            (false, true) => write!(f, " - ")?,
+            (false, false) => write!(f, " + ")?,
+        }
+        if magnitude != 1 || matches!(term, Term::Bias) {
+            write!(f, "{magnitude}")?;
+        }
+        match term {
+            Term::Ivar(ivar) if magnitude == 1 => write!(f, "i{ivar}")?,
+            Term::Ivar(ivar) => write!(f, "*i{ivar}")?,
+            Term::Quasi((_, op, operand, divisor)) => {
+                if magnitude != 1 {
+                    write!(f, "*")?;
+                }
+                write!(f, "{}({operand}, {divisor})", op.name())?;
+            }
+            Term::Bias => (),
+        }
+        empty = false;
+    }
+    if empty {
+        write!(f, "0")?;
+    }
+    Ok(())
+}
+
+/// Induction variables `parse_affine` accepts, so that untrusted text cannot
+/// make it allocate a coefficient per index up to a huge one.
+const MAX_PARSED_IVARS: usize = 1 << 16;
+
+/// Parse the notation produced by `Display` into `make(coefficients, bias,
+/// terms)`, with coefficients stripped of trailing zeros and the operand of
+/// each term parsed the same way.
+pub(crate) fn parse_affine<T>(
+    text: &str,
+    make: &dyn Fn(Vec<isize>, isize, Vec<TermParts<T>>) -> T,
+) -> Result<T, String> {
+    let text = text
+        .chars()
+        .filter(|x| !x.is_whitespace())
+        .collect::<String>();
+    if text.is_empty() {
+        return Err("empty expression".to_string());
+    }
+    let mut coefficients = Vec::<isize>::new();
+    let mut bias = 0isize;
+    let mut terms = Vec::new();
+    let mut rest = text.as_str();
+    while !rest.is_empty() {
+        let (sign, body) = match rest.as_bytes()[0] {
+            b'-' => (-1i128, &rest[1..]),
+            b'+' => (1, &rest[1..]),
+            _ => (1, rest),
+        };
+        // The next sign outside of the parentheses of a term ends it.
+        let mut depth = 0usize;
+        let end = body
+            .char_indices()
+            .find(|(_, x)| {
+                match x {
+                    '(' => depth += 1,
+                    ')' => depth = depth.saturating_sub(1),
+                    _ => (),
+                }
+                depth == 0 && matches!(x, '+' | '-')
+            })
+            .map_or(body.len(), |x| x.0);
+        let (term, tail) = body.split_at(end);
+        rest = tail;
+        // Magnitudes are parsed wider than `isize`, so that `-isize::MIN`
+        // does not overflow before the sign is applied.
+        let number = |x: &str| {
+            x.parse::<u64>()
+                .map(i128::from)
+                .map_err(|e| format!("invalid term `{term}`: {e}"))
+        };
+        let is_call = |x: &str| x.starts_with("floordiv(") || x.starts_with("mod(");
+        let (magnitude, factor) = match term.split_once('*') {
+            _ if is_call(term) => (1, Some(term)),
+            Some((coeff, factor)) => (number(coeff)?, Some(factor)),
+            None if term.starts_with('i') => (1, Some(term)),
+            None => (number(term)?, None),
+        };
+        let out_of_range = || format!("term `{term}` is out of range");
+        let value = isize::try_from(sign * magnitude).map_err(|_| out_of_range())?;
+        match factor {
+            Some(call) if is_call(call) => {
+                let (name, args) = call.split_once('(').unwrap();
+                let op = match name {
+                    "floordiv" => QuasiOp::FloorDiv,
+                    _ => QuasiOp::Mod,
+                };
+                let (operand, divisor) = args
+                    .strip_suffix(')')
+                    .and_then(|x| x.rsplit_once(','))
+                    .ok_or_else(|| format!("invalid term `{term}`"))?;
+                let divisor = divisor
+                    .parse::<isize>()
+                    .ok()
+                    .filter(|x| *x > 0)
+                    .ok_or_else(|| format!("divisor of `{term}` is not a positive integer"))?;
+                terms.push((value, op, parse_affine(operand, make)?, divisor));
+            }
+            Some(ivar) => {
+                let ivar = ivar
+                    .strip_prefix('i')
+                    .and_then(|x| x.parse::<usize>().ok())
+                    .ok_or_else(|| format!("invalid induction variable in `{term}`"))?;
+                if ivar >= MAX_PARSED_IVARS {
+                    return Err(format!("induction variable in `{term}` is out of range"));
+                }
+                if coefficients.len() <= ivar {
+                    coefficients.resize(ivar + 1, 0);
+                }
+                coefficients[ivar] = coefficients[ivar]
+                    .checked_add(value)
+                    .ok_or_else(out_of_range)?;
+            }
+            None => bias = bias.checked_add(value).ok_or_else(out_of_range)?,
+        }
+    }
+    let len = coefficients
+        .iter()
+        .rposition(|x| *x != 0)
+        .map_or(0, |x| x + 1);
+    coefficients.truncate(len);
+    Ok(make(coefficients, bias, terms))
+}
+
+/// Per-dimension subscripts of a multi-dimensional access, together with the
+/// byte stride of each dimension. Row-major and column-major layouts of the
+/// same memref only differ in `strides`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Subscripts<'a> {
+    indices: &'a [&'a Expr<'a>],
+    strides: &'a [isize],
+}
+
+impl<'a> Subscripts<'a> {
+    /// Subscripts with one entry of `indices` and `strides` per dimension, or
+    /// `None` if they have different lengths.
+    pub fn new(ctx: &'a Context, indices: &[&'a Expr<'a>], strides: &[isize]) -> Option<Self> {
+        (indices.len() == strides.len()).then(|| Self {
+            indices: ctx.arena.alloc_slice_copy(indices),
+            strides: ctx.arena.alloc_slice_copy(strides),
+        })
+    }
+    pub fn indices(&self) -> &'a [&'a Expr<'a>] {
+        self.indices
+    }
+    pub fn strides(&self) -> &'a [isize] {
+        self.strides
+    }
+    pub fn rank(&self) -> usize {
+        self.indices.len()
+    }
+    /// The linear byte offset `sum(strides[d] * indices[d])`.
+    pub fn linearize(&self, ctx: &'a Context) -> Expr<'a> {
+        let len = self
+            .indices
+            .iter()
+            .map(|x| x.coefficent.len())
+            .max()
+            .unwrap_or(0);
+        let mut coefficent = vec![0; len];
+        let mut bias = 0;
+        let mut terms = Vec::new();
+        for (index, stride) in self.indices.iter().zip(self.strides) {
+            for (acc, coeff) in coefficent.iter_mut().zip(index.coefficent) {
+                *acc += stride * coeff;
+            }
+            bias += stride * index.bias;
+            terms.extend(index.terms.iter().map(|x| QuasiTerm {
+                coefficient: stride * x.coefficient,
+                ..x.clone()
+            }));
+        }
+        Expr {
+            terms: ctx.arena.alloc_slice_clone(&terms),
+            ..Expr::new(ctx, &coefficent, bias)
+        }
+    }
 }
 
 #[no_mangle]
@@ -36,6 +551,33 @@ pub unsafe extern "C" fn slap_expr_new<'a>(
         .get_mut()
 }
 
+/*
+slap_expr_t slap_expr_new_quasi(slap_context_t, ssize_t *coeffs, size_t n,
+                                ssize_t bias, const slap_quasi_term_t *terms,
+                                size_t term_count);
+*/
+
+#[no_mangle]
+pub unsafe extern "C" fn slap_expr_new_quasi<'a>(
+    ctx: *mut Context,
+    coefficent: *const isize,
+    len: usize,
+    bias: isize,
+    terms: *const QuasiTerm<'a>,
+    term_count: usize,
+) -> *mut Expr<'a> {
+    let ctx = &*ctx;
+    let coefficent = unsafe { std::slice::from_raw_parts(coefficent, len) };
+    let terms = match term_count {
+        0 => &[],
+        _ => unsafe { std::slice::from_raw_parts(terms, term_count) },
+    };
+    match Expr::with_terms(ctx, coefficent, bias, terms) {
+        Some(expr) => ctx.arena.alloc(UnsafeCell::new(expr)).get_mut(),
+        None => std::ptr::null_mut(),
+    }
+}
+
 /*
 ssize_t *slap_expr_get_coefficients(slap_expr_t);
 size_t slap_expr_get_length(slap_expr_t);
@@ -49,10 +591,223 @@ pub unsafe extern "C" fn slap_expr_get_coefficients(expr: *const Expr) -> *const
 
 #[no_mangle]
 pub unsafe extern "C" fn slap_expr_get_length(expr: *const Expr) -> usize {
-    (*expr).coefficent.len()
+    let expr = &*expr;
+    expr.coefficent.len()
 }
 
 #[no_mangle]
 pub unsafe extern "C" fn slap_expr_get_bias(expr: *const Expr) -> isize {
     (*expr).bias
 }
+
+/*
+size_t slap_expr_get_term_count(slap_expr_t);
+ssize_t slap_expr_get_term_coefficient(slap_expr_t, size_t);
+slap_quasi_op_t slap_expr_get_term_op(slap_expr_t, size_t);
+ssize_t slap_expr_get_term_divisor(slap_expr_t, size_t);
+slap_expr_t slap_expr_get_term_operand(slap_expr_t, size_t);
+*/
+
+#[no_mangle]
+pub unsafe extern "C" fn slap_expr_get_term_count(expr: *const Expr) -> usize {
+    let expr = &*expr;
+    expr.terms.len()
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn slap_expr_get_term_coefficient(expr: *const Expr, term: usize) -> isize {
+    let expr = &*expr;
+    expr.terms[term].coefficient
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn slap_expr_get_term_op(expr: *const Expr, term: usize) -> QuasiOp {
+    let expr = &*expr;
+    expr.terms[term].op
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn slap_expr_get_term_divisor(expr: *const Expr, term: usize) -> isize {
+    let expr = &*expr;
+    expr.terms[term].divisor
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn slap_expr_get_term_operand<'a>(
+    expr: *const Expr<'a>,
+    term: usize,
+) -> *const Expr<'a> {
+    let expr = &*expr;
+    expr.terms[term].operand
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quasi_terms_round_down() {
+        let ctx = Context::with_capacity(4096);
+        let i0 = Expr::new(&ctx, &[1], 0);
+        let term = |op| QuasiTerm {
+            coefficient: 1,
+            op,
+            operand: &i0,
+            divisor: 2,
+        };
+        let div = Expr::with_terms(&ctx, &[], 0, &[term(QuasiOp::FloorDiv)]).unwrap();
+        let rem = Expr::with_terms(&ctx, &[], 0, &[term(QuasiOp::Mod)]).unwrap();
+        assert_eq!(div.eval(&[-7]), Ok(-4));
+        assert_eq!(rem.eval(&[-7]), Ok(1));
+        assert_eq!(div.eval(&[7]), Ok(3));
+        assert_eq!(rem.eval(&[7]), Ok(1));
+        assert_eq!(div.to_string(), "floordiv(i0, 2)");
+        assert!(!div.is_affine());
+        assert_eq!(div.constant(), None);
+        assert_ne!(div, rem);
+        let zero = QuasiTerm {
+            divisor: 0,
+            ..term(QuasiOp::Mod)
+        };
+        assert!(Expr::with_terms(&ctx, &[], 0, &[zero]).is_none());
+    }
+
+    #[test]
+    fn ffi_builds_quasi_terms() {
+        let ctx = Context::with_capacity(4096);
+        let ctx_ptr = &ctx as *const Context as *mut Context;
+        let i0 = Expr::new(&ctx, &[1], 0);
+        let mut terms = [QuasiTerm {
+            coefficient: 3,
+            op: QuasiOp::Mod,
+            operand: &i0,
+            divisor: 4,
+        }];
+        unsafe {
+            let expr = slap_expr_new_quasi(ctx_ptr, [0, 2].as_ptr(), 2, 1, terms.as_ptr(), 1);
+            assert_eq!((*expr).to_string(), "2*i1 + 3*mod(i0, 4) + 1");
+            assert_eq!(slap_expr_get_term_count(expr), 1);
+            assert_eq!(slap_expr_get_term_op(expr, 0), QuasiOp::Mod);
+            assert_eq!(slap_expr_get_term_operand(expr, 0), &i0 as *const Expr);
+            let affine = slap_expr_new_quasi(ctx_ptr, [1].as_ptr(), 1, 0, std::ptr::null(), 0);
+            assert_eq!(*affine, i0);
+            terms[0].divisor = 0;
+            let rejected = slap_expr_new_quasi(ctx_ptr, [].as_ptr(), 0, 0, terms.as_ptr(), 1);
+            assert!(rejected.is_null());
+        }
+    }
+
+    #[test]
+    fn range_bounds_every_value() {
+        let ctx = Context::with_capacity(4096);
+        let i0 = Expr::new(&ctx, &[1], -8);
+        let term = |op, divisor| QuasiTerm {
+            coefficient: -2,
+            op,
+            operand: &i0,
+            divisor,
+        };
+        let ivars = [0..=9, -3..=1];
+        for expr in [
+            Expr::new(&ctx, &[3, -1], 1),
+            Expr::with_terms(&ctx, &[], 0, &[term(QuasiOp::FloorDiv, 4)]).unwrap(),
+            Expr::with_terms(&ctx, &[], 0, &[term(QuasiOp::Mod, 4)]).unwrap(),
+            Expr::with_terms(&ctx, &[], 0, &[term(QuasiOp::Mod, 16)]).unwrap(),
+        ] {
+            let values = ivars[0]
+                .clone()
+                .flat_map(|x| ivars[1].clone().map(move |y| [x, y]))
+                .map(|x| expr.eval(&x).unwrap())
+                .collect::<Vec<_>>();
+            let range = expr.range(&ivars);
+            assert_eq!(range.start(), values.iter().min().unwrap(), "{expr}");
+            assert_eq!(range.end(), values.iter().max().unwrap(), "{expr}");
+        }
+        let unbounded = Expr::new(&ctx, &[isize::MAX, isize::MAX], 0);
+        assert_eq!(unbounded.range(&[]), isize::MIN..=isize::MAX);
+    }
+
+    #[test]
+    fn simplify_keeps_eval() {
+        let ctx = Context::with_capacity(1 << 16);
+        let mut state = 0x9e37_79b9_7f4a_7c15u64;
+        let mut next = |range: isize| {
+            state = state
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            (state >> 33) as isize % (2 * range + 1) - range
+        };
+        for _ in 0..200 {
+            // Up to four random coefficients followed by one to three zeros.
+            let len = next(2) + 2;
+            let zeros = next(1) + 2;
+            let mut coefficients = (0..len).map(|_| next(16)).collect::<Vec<_>>();
+            coefficients.resize((len + zeros) as usize, 0);
+            // Terms on a random or a constant operand, some of them repeated.
+            let operands = [
+                Expr::new(&ctx, &coefficients[..len as usize], next(16)),
+                Expr::new(&ctx, &[0, 0], next(16)),
+            ];
+            let ops = [QuasiOp::FloorDiv, QuasiOp::Mod];
+            let divisor = next(3) + 5;
+            let mut terms = (0..3)
+                .map(|_| QuasiTerm {
+                    coefficient: next(2),
+                    op: ops[next(1).unsigned_abs()],
+                    operand: &operands[next(1).unsigned_abs()],
+                    divisor,
+                })
+                .collect::<Vec<_>>();
+            let expr = Expr::with_terms(&ctx, &coefficients, next(100), &terms).unwrap();
+            terms.reverse();
+            let reversed = Expr::with_terms(&ctx, &coefficients, expr.bias(), &terms).unwrap();
+            let simple = expr.simplify(&ctx);
+            assert!(simple.coefficients().last() != Some(&0));
+            assert_eq!(simple.simplify(&ctx), simple);
+            assert_eq!(reversed.simplify(&ctx), simple);
+            assert!(simple.terms().iter().all(|x| x.coefficient != 0));
+            assert!(simple
+                .terms()
+                .iter()
+                .all(|x| x.operand.constant().is_none()));
+            for _ in 0..10 {
+                let ivars = (0..len + zeros).map(|_| next(1000)).collect::<Vec<_>>();
+                assert_eq!(simple.eval(&ivars), expr.eval(&ivars));
+                let bound = &ivars[..simple.ivar_count()];
+                assert_eq!(simple.eval(bound), expr.eval(&ivars));
+            }
+        }
+    }
+
+    #[test]
+    fn simplify_normalizes_terms() {
+        let ctx = Context::with_capacity(4096);
+        let i0 = Expr::new(&ctx, &[1, 0], 0);
+        let seven = Expr::new(&ctx, &[], 7);
+        let term = |coefficient, op, operand| QuasiTerm {
+            coefficient,
+            op,
+            operand,
+            divisor: 2,
+        };
+        let expr = Expr::with_terms(
+            &ctx,
+            &[0, 0],
+            1,
+            &[
+                term(3, QuasiOp::Mod, &i0),
+                term(2, QuasiOp::FloorDiv, &i0),
+                term(4, QuasiOp::FloorDiv, &seven),
+                term(-3, QuasiOp::FloorDiv, &i0),
+                term(0, QuasiOp::Mod, &seven),
+            ],
+        )
+        .unwrap();
+        let simple = expr.simplify(&ctx);
+        assert_eq!(simple.to_string(), "-floordiv(i0, 2) + 3*mod(i0, 2) + 13");
+        assert_eq!(simple.terms()[0].operand.coefficients(), [1]);
+        for x in -5..5 {
+            assert_eq!(simple.eval(&[x]), expr.eval(&[x]));
+        }
+    }
+}
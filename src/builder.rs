@@ -0,0 +1,538 @@
+use std::{cell::UnsafeCell, ptr::NonNull};
+
+use rustc_hash::{FxHashMap, FxHashSet};
+
+use crate::{
+    affine::{Expr, QuasiOp, QuasiTerm, Subscripts},
+    graph::{AccessKind, Graph},
+    Context,
+};
+
+/// Safe Rust counterpart of the `slap_graph_new_*` functions: allocates nodes
+/// into the context arena and hands out [`GraphRef`]s that can be rewired
+/// afterwards, so cyclic loop bodies can be built without raw pointers.
+#[derive(Clone, Copy)]
+pub struct GraphBuilder<'a> {
+    ctx: &'a Context,
+}
+
+/// Handle to a node allocated by a [`GraphBuilder`].
+#[derive(Clone, Copy)]
+pub struct GraphRef<'a> {
+    cell: &'a UnsafeCell<Graph<'a>>,
+}
+
+/// A [`GraphRef`] setter was called on a node kind that lacks that edge.
+#[derive(Debug, Clone, Copy)]
+pub struct WrongVariant;
+
+impl std::fmt::Display for WrongVariant {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "node has no such edge")
+    }
+}
+
+impl std::error::Error for WrongVariant {}
+
+impl<'a> GraphBuilder<'a> {
+    pub fn new(ctx: &'a Context) -> Self {
+        Self { ctx }
+    }
+    pub fn expr(&self, coefficent: &[isize], bias: isize) -> &'a Expr<'a> {
+        self.ctx.arena.alloc(Expr::new(self.ctx, coefficent, bias))
+    }
+    /// `operand floordiv divisor`, rounding towards negative infinity.
+    /// `None` unless the divisor is positive.
+    pub fn floordiv(&self, operand: &'a Expr<'a>, divisor: isize) -> Option<&'a Expr<'a>> {
+        self.quasi(QuasiOp::FloorDiv, operand, divisor)
+    }
+    /// `operand mod divisor`, in `0..divisor`. `None` unless the divisor is
+    /// positive.
+    pub fn modulo(&self, operand: &'a Expr<'a>, divisor: isize) -> Option<&'a Expr<'a>> {
+        self.quasi(QuasiOp::Mod, operand, divisor)
+    }
+    fn quasi(&self, op: QuasiOp, operand: &'a Expr<'a>, divisor: isize) -> Option<&'a Expr<'a>> {
+        let term = QuasiTerm {
+            coefficient: 1,
+            op,
+            operand,
+            divisor,
+        };
+        let expr = Expr::with_terms(self.ctx, &[], 0, &[term])?;
+        Some(self.ctx.arena.alloc(expr))
+    }
+    fn alloc(&self, graph: Graph<'a>) -> GraphRef<'a> {
+        GraphRef {
+            cell: self.ctx.arena.alloc(UnsafeCell::new(graph)),
+        }
+    }
+    pub fn start(&self, next: Option<GraphRef<'a>>) -> GraphRef<'a> {
+        self.alloc(Graph::Start(next.map(GraphRef::get)))
+    }
+    pub fn end(&self) -> GraphRef<'a> {
+        self.alloc(Graph::End)
+    }
+    pub fn access(
+        &self,
+        memref: usize,
+        offset: &'a Expr<'a>,
+        kind: AccessKind,
+        next: Option<GraphRef<'a>>,
+    ) -> GraphRef<'a> {
+        self.alloc(Graph::Access {
+            memref,
+            offset,
+            subscripts: None,
+            kind,
+            next: next.map(GraphRef::get),
+        })
+    }
+    /// Access with one index per dimension; the offset is linearized from
+    /// `indices` and the byte `strides` of each dimension. `None` if they
+    /// have different lengths.
+    pub fn access_nd(
+        &self,
+        memref: usize,
+        indices: &[&'a Expr<'a>],
+        strides: &[isize],
+        kind: AccessKind,
+        next: Option<GraphRef<'a>>,
+    ) -> Option<GraphRef<'a>> {
+        let subscripts = &*self
+            .ctx
+            .arena
+            .alloc(Subscripts::new(self.ctx, indices, strides)?);
+        Some(self.alloc(Graph::Access {
+            memref,
+            offset: self.ctx.arena.alloc(subscripts.linearize(self.ctx)),
+            subscripts: Some(subscripts),
+            kind,
+            next: next.map(GraphRef::get),
+        }))
+    }
+    pub fn update(
+        &self,
+        ivar: usize,
+        expr: &'a Expr<'a>,
+        next: Option<GraphRef<'a>>,
+    ) -> GraphRef<'a> {
+        self.alloc(Graph::Update {
+            ivar,
+            expr,
+            next: next.map(GraphRef::get),
+        })
+    }
+    pub fn branch(
+        &self,
+        ivar: usize,
+        bound: &'a Expr<'a>,
+        then: Option<GraphRef<'a>>,
+        r#else: Option<GraphRef<'a>>,
+    ) -> GraphRef<'a> {
+        self.alloc(Graph::Branch {
+            ivar,
+            bound,
+            then: then.map(GraphRef::get),
+            r#else: r#else.map(GraphRef::get),
+        })
+    }
+}
+
+impl<'a> GraphRef<'a> {
+    pub fn get(self) -> &'a Graph<'a> {
+        unsafe { &*self.cell.get() }
+    }
+    /// Set the successor of a `Start`, `Access` or `Update` node. Unlike
+    /// `slap_graph_*_set_next`, other variants are reported as an error.
+    ///
+    /// # Safety
+    ///
+    /// The node is written in place, while references to it handed out by
+    /// [`GraphRef::get`] or stored in the edges of other nodes stay valid.
+    /// No such reference may be in use during the call, and the node must
+    /// not be traversed or simulated concurrently; as with the FFI setters,
+    /// rewire the graph before analysing it.
+    pub unsafe fn set_next(self, next: Option<GraphRef<'a>>) -> Result<(), WrongVariant> {
+        match &mut *self.cell.get() {
+            Graph::Start(field)
+            | Graph::Access { next: field, .. }
+            | Graph::Update { next: field, .. } => *field = next.map(GraphRef::get),
+            _ => return Err(WrongVariant),
+        }
+        Ok(())
+    }
+    /// Set the `then` side of a `Branch` node.
+    ///
+    /// # Safety
+    ///
+    /// Same contract as [`GraphRef::set_next`].
+    pub unsafe fn set_then(self, then: Option<GraphRef<'a>>) -> Result<(), WrongVariant> {
+        match &mut *self.cell.get() {
+            Graph::Branch { then: field, .. } => *field = then.map(GraphRef::get),
+            _ => return Err(WrongVariant),
+        }
+        Ok(())
+    }
+    /// Set the `else` side of a `Branch` node.
+    ///
+    /// # Safety
+    ///
+    /// Same contract as [`GraphRef::set_next`].
+    pub unsafe fn set_else(self, r#else: Option<GraphRef<'a>>) -> Result<(), WrongVariant> {
+        match &mut *self.cell.get() {
+            Graph::Branch { r#else: field, .. } => *field = r#else.map(GraphRef::get),
+            _ => return Err(WrongVariant),
+        }
+        Ok(())
+    }
+}
+
+impl<'a> Graph<'a> {
+    /// Copy every node reachable from `self` into `ctx`, preserving shared
+    /// successors and back-edges, so the copy can be rewired independently.
+    pub fn deep_clone_into<'b>(&'a self, ctx: &'b Context) -> &'b Graph<'b> {
+        self.clone_redirected(ctx, |x| x)
+    }
+    /// Copy the graph into `ctx` as if every edge to a node `x`, and the root
+    /// itself, pointed to `redirect(x)` instead. Nodes only reachable through
+    /// redirected edges are left out.
+    pub(crate) fn clone_redirected<'b>(
+        &'a self,
+        ctx: &'b Context,
+        redirect: impl Fn(&'a Graph<'a>) -> &'a Graph<'a>,
+    ) -> &'b Graph<'b> {
+        let builder = GraphBuilder::new(ctx);
+        let expr = |x: &Expr| x.clone_into(ctx);
+        let root = redirect(self);
+        let mut nodes = Vec::new();
+        let mut visited = FxHashSet::default();
+        let mut stack = vec![root];
+        while let Some(node) = stack.pop() {
+            if visited.insert(NonNull::from(node)) {
+                nodes.push(node);
+                stack.extend(node.successors().map(&redirect));
+            }
+        }
+        let refs: FxHashMap<NonNull<Graph<'a>>, GraphRef<'b>> = nodes
+            .into_iter()
+            .map(|node| {
+                let copy = match *node {
+                    Graph::Start(_) => builder.start(None),
+                    Graph::End => builder.end(),
+                    Graph::Access {
+                        memref,
+                        offset,
+                        subscripts: None,
+                        kind,
+                        ..
+                    } => builder.access(memref, expr(offset), kind, None),
+                    Graph::Access {
+                        memref,
+                        subscripts: Some(subscripts),
+                        kind,
+                        ..
+                    } => {
+                        let indices = subscripts
+                            .indices()
+                            .iter()
+                            .map(|x| expr(x))
+                            .collect::<Vec<_>>();
+                        // One stride per index, as in the original.
+                        let copy =
+                            builder.access_nd(memref, &indices, subscripts.strides(), kind, None);
+                        copy.unwrap_or_else(|| unreachable!())
+                    }
+                    Graph::Update { ivar, expr: e, .. } => builder.update(ivar, expr(e), None),
+                    Graph::Branch { ivar, bound, .. } => {
+                        builder.branch(ivar, expr(bound), None, None)
+                    }
+                };
+                (NonNull::from(node), copy)
+            })
+            .collect();
+        let get = |x: Option<&'a Graph<'a>>| x.map(|x| refs[&NonNull::from(redirect(x))]);
+        // Every copy has the variant of its original, so the setters succeed.
+        // The copies are fresh and nobody outside holds references to them.
+        for (node, copy) in refs.iter() {
+            let result = match *unsafe { node.as_ref() } {
+                Graph::Start(next) | Graph::Access { next, .. } | Graph::Update { next, .. } => unsafe {
+                    copy.set_next(get(next))
+                },
+                Graph::Branch { then, r#else, .. } => unsafe {
+                    copy.set_then(get(then)).and(copy.set_else(get(r#else)))
+                },
+                Graph::End => Ok(()),
+            };
+            debug_assert!(result.is_ok());
+        }
+        refs[&NonNull::from(root)].get()
+    }
+}
+
+/// Why [`Graph::splice_before`] could not insert a node.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpliceError {
+    /// The target is the root, which has no incoming edge to retarget.
+    TargetIsRoot,
+    /// The inserted node is not an `Access` or `Update`, so it has no `next`
+    /// to continue to the target.
+    NodeWithoutNext,
+}
+
+impl std::fmt::Display for SpliceError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SpliceError::TargetIsRoot => write!(f, "cannot splice before the root"),
+            SpliceError::NodeWithoutNext => write!(f, "spliced node must be an access or update"),
+        }
+    }
+}
+
+impl std::error::Error for SpliceError {}
+
+impl<'a> Graph<'a> {
+    /// Insert `node` in front of `target` in the graph rooted at `self`:
+    /// every edge into `target`, from any number of predecessors, is pointed
+    /// at `node`, whose `next` becomes `target`.
+    ///
+    /// This rewires the graph in place, so `populate_node_info` must run
+    /// again before simulating.
+    ///
+    /// # Safety
+    ///
+    /// Every node reachable from `self`, and `node`, must have been allocated
+    /// as an `UnsafeCell<Graph>`, the way [`GraphBuilder`] and the
+    /// `slap_graph_new_*` functions do. The contract of
+    /// [`GraphRef::set_next`] applies to all of them: no reference into the
+    /// graph may be read while it is rewired.
+    pub unsafe fn splice_before(
+        &'a self,
+        target: &'a Graph<'a>,
+        node: &'a Graph<'a>,
+    ) -> Result<(), SpliceError> {
+        if std::ptr::eq(self, target) {
+            return Err(SpliceError::TargetIsRoot);
+        }
+        if !matches!(node, Graph::Access { .. } | Graph::Update { .. }) {
+            return Err(SpliceError::NodeWithoutNext);
+        }
+        let retarget = |field: &mut Option<&'a Graph<'a>>| {
+            if field.is_some_and(|x| std::ptr::eq(x, target)) {
+                *field = Some(node);
+            }
+        };
+        let nodes = self.iter().collect::<Vec<_>>();
+        for pred in nodes {
+            match &mut *NonNull::from(pred).as_ptr() {
+                Graph::Start(next) | Graph::Access { next, .. } | Graph::Update { next, .. } => {
+                    retarget(next)
+                }
+                Graph::Branch { then, r#else, .. } => {
+                    retarget(then);
+                    retarget(r#else);
+                }
+                Graph::End => (),
+            }
+        }
+        match &mut *NonNull::from(node).as_ptr() {
+            Graph::Access { next, .. } | Graph::Update { next, .. } => *next = Some(target),
+            _ => unreachable!(),
+        }
+        Ok(())
+    }
+}
+
+/// One `affine.for` level of a [`LoopNestBuilder`]: the induction variable
+/// runs from `lower` while it is below `upper`, advancing by `step`.
+#[derive(Clone, Copy)]
+struct LoopLevel<'a> {
+    lower: &'a Expr<'a>,
+    upper: &'a Expr<'a>,
+    step: isize,
+}
+
+/// Builds the graph of a perfect loop nest the way the extractor lowers
+/// `affine.for`: each level is an `Update` to its lower bound followed by a
+/// `Branch` on the upper bound, whose `then` side runs the inner levels and
+/// ends in an increment `Update` back to the `Branch`. The accesses form the
+/// body of the innermost level. Level `d` uses induction variable `d`.
+pub struct LoopNestBuilder<'a> {
+    builder: GraphBuilder<'a>,
+    loops: Vec<LoopLevel<'a>>,
+    body: Vec<(usize, &'a Expr<'a>, AccessKind)>,
+}
+
+impl<'a> LoopNestBuilder<'a> {
+    pub fn new(ctx: &'a Context) -> Self {
+        Self {
+            builder: GraphBuilder::new(ctx),
+            loops: Vec::new(),
+            body: Vec::new(),
+        }
+    }
+    /// Add a level inside the previously added ones. Bounds may refer to the
+    /// induction variables of outer levels.
+    ///
+    /// Panics if `step` is not positive, as in `affine.for`: the level only
+    /// exits once its induction variable reaches `upper`.
+    pub fn for_loop(mut self, lower: &'a Expr<'a>, upper: &'a Expr<'a>, step: isize) -> Self {
+        assert!(step > 0, "loop step must be positive, got {step}");
+        self.loops.push(LoopLevel { lower, upper, step });
+        self
+    }
+    /// Append an access to the innermost body, after the ones added so far.
+    pub fn access(mut self, memref: usize, offset: &'a Expr<'a>, kind: AccessKind) -> Self {
+        self.body.push((memref, offset, kind));
+        self
+    }
+    pub fn load(self, memref: usize, offset: &'a Expr<'a>) -> Self {
+        self.access(memref, offset, AccessKind::Read)
+    }
+    pub fn store(self, memref: usize, offset: &'a Expr<'a>) -> Self {
+        self.access(memref, offset, AccessKind::Write)
+    }
+    /// Allocate the nest, returning its `Start` node. The outermost loop
+    /// exits to an `End` node.
+    pub fn build(self) -> &'a Graph<'a> {
+        let end = self.builder.end();
+        let nest = self.build_level(0, end);
+        self.builder.start(Some(nest)).get()
+    }
+    /// Level `depth` and everything inside it, continuing to `exit` once the
+    /// loop is done.
+    fn build_level(&self, depth: usize, exit: GraphRef<'a>) -> GraphRef<'a> {
+        let Some(level) = self.loops.get(depth) else {
+            return self
+                .body
+                .iter()
+                .rev()
+                .fold(exit, |next, &(memref, offset, kind)| {
+                    self.builder.access(memref, offset, kind, Some(next))
+                });
+        };
+        let mut coefficients = vec![0; depth + 1];
+        coefficients[depth] = 1;
+        let increment = self.builder.expr(&coefficients, level.step);
+        let cond = self.builder.branch(depth, level.upper, None, Some(exit));
+        let epilogue = self.builder.update(depth, increment, Some(cond));
+        let body = self.build_level(depth + 1, epilogue);
+        // `cond` was just allocated and is only reachable from this nest.
+        let result = unsafe { cond.set_then(Some(body)) };
+        debug_assert!(result.is_ok());
+        self.builder.update(depth, level.lower, Some(cond))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use super::*;
+    use crate::simulator::{DistanceMode, SimulationCtx};
+
+    #[test]
+    fn loop_nest_matches_hand_built_loop() {
+        let ctx = Context::with_capacity(4096);
+        let lower = GraphBuilder::new(&ctx).expr(&[], 0);
+        let upper = GraphBuilder::new(&ctx).expr(&[], 4);
+        let offset = GraphBuilder::new(&ctx).expr(&[8], 0);
+        let nest = LoopNestBuilder::new(&ctx)
+            .for_loop(lower, upper, 1)
+            .load(0, offset)
+            .build();
+
+        let builder = GraphBuilder::new(&ctx);
+        let end = builder.end();
+        let cond = builder.branch(0, upper, None, Some(end));
+        let step = builder.update(0, builder.expr(&[1], 1), Some(cond));
+        let body = builder.access(0, offset, AccessKind::Read, Some(step));
+        unsafe { cond.set_then(Some(body)) }.unwrap();
+        let init = builder.update(0, lower, Some(cond));
+        let start = builder.start(Some(init));
+
+        assert!(nest.structurally_eq(start.get()));
+        assert!(unsafe { cond.set_next(Some(end)) }.is_err());
+        assert!(unsafe { body.set_else(Some(end)) }.is_err());
+    }
+
+    #[test]
+    #[should_panic(expected = "loop step must be positive")]
+    fn loop_nest_rejects_non_positive_step() {
+        let ctx = Context::with_capacity(4096);
+        let builder = GraphBuilder::new(&ctx);
+        LoopNestBuilder::new(&ctx).for_loop(builder.expr(&[], 4), builder.expr(&[], 0), 0);
+    }
+
+    #[test]
+    fn simulate_built_loop() {
+        let ctx = Context::with_capacity(4096);
+        let builder = GraphBuilder::new(&ctx);
+        let graph = LoopNestBuilder::new(&ctx)
+            .for_loop(builder.expr(&[], 0), builder.expr(&[], 4), 1)
+            .load(0, builder.expr(&[8], 0))
+            .build();
+        let vaddrs = [0];
+        let mut sctx = SimulationCtx::new(&ctx, 64, &vaddrs, DistanceMode::LogicalTime).unwrap();
+        sctx.populate_node_info(graph);
+        sctx.run(graph).unwrap();
+        let access = graph
+            .iter()
+            .find(|x| matches!(x, Graph::Access { .. }))
+            .unwrap();
+        // Four accesses to one block: a cold miss, then three reuses.
+        let dist = sctx.get_node_dist(access).unwrap();
+        assert_eq!(dist.iter().collect::<Vec<_>>(), [(&1, &3)]);
+    }
+
+    #[test]
+    fn simulate_spliced_access() {
+        let trace = RefCell::new(Vec::new());
+        let ctx = Context::with_capacity(4096);
+        let builder = GraphBuilder::new(&ctx);
+        let zero = builder.expr(&[], 0);
+        let second = builder.access(0, zero, AccessKind::Read, Some(builder.end()));
+        let first = builder.access(0, zero, AccessKind::Read, Some(second));
+        let graph = builder.start(Some(first)).get();
+        let inserted = builder.access(0, builder.expr(&[], 64), AccessKind::Write, None);
+        unsafe { graph.splice_before(second.get(), inserted.get()) }.unwrap();
+        assert!(matches!(
+            unsafe { graph.splice_before(graph, inserted.get()) },
+            Err(SpliceError::TargetIsRoot)
+        ));
+
+        let vaddrs = [0];
+        let mut sctx = SimulationCtx::new(&ctx, 64, &vaddrs, DistanceMode::LogicalTime)
+            .unwrap()
+            .with_access_hook(|node, _, interval| trace.borrow_mut().push((node, interval)));
+        sctx.populate_node_info(graph);
+        sctx.run(graph).unwrap();
+        drop(sctx);
+        // The inserted access to another block runs between the two others,
+        // stretching the reuse of block 0 to two accesses.
+        let trace = trace.into_inner();
+        assert_eq!(
+            trace.iter().map(|x| x.1).collect::<Vec<_>>(),
+            [None, None, Some(2)]
+        );
+        assert_eq!(trace[0].0, 0);
+        assert_eq!(trace[1].0, 1);
+        assert_eq!(trace[2].0, 2);
+    }
+
+    #[test]
+    fn access_nd_checks_rank() {
+        let ctx = Context::with_capacity(4096);
+        let builder = GraphBuilder::new(&ctx);
+        let (i, j) = (builder.expr(&[1], 0), builder.expr(&[0, 1], 0));
+        assert!(builder
+            .access_nd(0, &[i, j], &[8], AccessKind::Read, None)
+            .is_none());
+        let access = builder
+            .access_nd(0, &[i, j], &[64, 8], AccessKind::Read, None)
+            .unwrap();
+        let Graph::Access { offset, .. } = access.get() else {
+            unreachable!()
+        };
+        assert_eq!(offset.coefficients(), [64, 8]);
+    }
+}
@@ -50,12 +50,13 @@ class ExtractContext {
   affine::AffineForOp parent;
   slap_graph_t parent_epilogue;
   size_t assigned_address;
+  bool unsupported_expr;
   void recordIVar(Value ivar) { ivars.try_emplace(ivar, ivars.size()); }
 
 public:
   ExtractContext(slap_context_t ctx, affine::AffineForOp entry)
       : slap_ctx(ctx), ivars{}, parent(nullptr), parent_epilogue(nullptr),
-        assigned_address(0) {
+        assigned_address(0), unsupported_expr(false) {
     recordIVar(entry.getInductionVar());
     entry.walk(
         [&](affine::AffineForOp op) { recordIVar(op.getInductionVar()); });
@@ -69,6 +70,8 @@ public:
     }
     return it->second;
   }
+  void reportUnsupportedExpr() { unsupported_expr = true; }
+  bool hasUnsupportedExpr() { return unsupported_expr; }
   slap_graph_t getParentEpilogue() { return parent_epilogue; }
   void setParentCondEpilogque(slap_graph_t parent) { parent_epilogue = parent; }
   affine::AffineForOp getParent() { return parent; }
@@ -114,114 +117,131 @@ public:
 
 struct AffineContext {
   ExtractContext &ext_ctx;
-  std::optional<AffineExpr> parent;
   llvm::SmallVector<ssize_t> coeff;
   ssize_t bias;
+  llvm::SmallVector<slap_quasi_term_t> terms;
   OperandRange operands;
 
   size_t getIVar(Value ivar) { return ext_ctx.getIVar(ivar); }
+  slap_expr_t build() {
+    return slap_expr_new_quasi(ext_ctx.getSLAPContext(), coeff.data(),
+                               coeff.size(), bias, terms.data(), terms.size());
+  }
 };
 
-void extractAffineExpr(AffineExpr expr, AffineContext &ctx) {
+AffineContext newAffineContext(ExtractContext &ctx, OperandRange operands) {
+  return AffineContext{
+      .ext_ctx = ctx,
+      .coeff = llvm::SmallVector<ssize_t>(ctx.getNumOfIvars(), 0),
+      .bias = 0,
+      .terms = {},
+      .operands = operands,
+  };
+}
+
+// Add `scale * expr` to `ctx`. Returns false for ceildiv, symbols and products
+// of two non-constant expressions, which slap expressions cannot represent.
+bool extractAffineExpr(AffineExpr expr, ssize_t scale, AffineContext &ctx) {
   switch (expr.getKind()) {
   case AffineExprKind::Add: {
     auto add = cast<AffineBinaryOpExpr>(expr);
-    extractAffineExpr(add.getLHS(), ctx);
-    extractAffineExpr(add.getRHS(), ctx);
-    break;
+    return extractAffineExpr(add.getLHS(), scale, ctx) &&
+           extractAffineExpr(add.getRHS(), scale, ctx);
   }
   case AffineExprKind::Mul: {
     auto mul = cast<AffineBinaryOpExpr>(expr);
-    if (!isa<AffineDimExpr>(mul.getLHS()))
-      llvm_unreachable(
-          "nested affine expression under multiplication is not supported");
-    auto old_parent = ctx.parent;
-    ctx.parent = expr;
-    extractAffineExpr(mul.getRHS(), ctx);
-    ctx.parent = old_parent;
-    break;
+    if (auto rhs = dyn_cast<AffineConstantExpr>(mul.getRHS()))
+      return extractAffineExpr(mul.getLHS(), scale * rhs.getValue(), ctx);
+    if (auto lhs = dyn_cast<AffineConstantExpr>(mul.getLHS()))
+      return extractAffineExpr(mul.getRHS(), scale * lhs.getValue(), ctx);
+    return false;
   }
   case AffineExprKind::Constant: {
     auto constant = cast<AffineConstantExpr>(expr);
-    if (!ctx.parent || ctx.parent->getKind() == AffineExprKind::Add)
-      ctx.bias = constant.getValue();
-    else {
-      auto mul = cast<AffineBinaryOpExpr>(*ctx.parent);
-      auto dim = cast<AffineDimExpr>(mul.getLHS());
-      auto pos = dim.getPosition();
-      auto value = ctx.operands[pos];
-      ctx.coeff[ctx.getIVar(value)] = constant.getValue();
-    }
-    break;
+    ctx.bias += scale * constant.getValue();
+    return true;
   }
   case AffineExprKind::DimId: {
     auto dim = cast<AffineDimExpr>(expr);
     auto pos = dim.getPosition();
     auto value = ctx.operands[pos];
-    ctx.coeff[ctx.getIVar(value)] = 1;
-    break;
+    ctx.coeff[ctx.getIVar(value)] += scale;
+    return true;
   }
-  case AffineExprKind::SymbolId:
   case AffineExprKind::Mod:
-  case AffineExprKind::FloorDiv:
+  case AffineExprKind::FloorDiv: {
+    auto div = cast<AffineBinaryOpExpr>(expr);
+    auto divisor = dyn_cast<AffineConstantExpr>(div.getRHS());
+    if (!divisor || divisor.getValue() <= 0)
+      return false;
+    auto operand = newAffineContext(ctx.ext_ctx, ctx.operands);
+    if (!extractAffineExpr(div.getLHS(), 1, operand))
+      return false;
+    auto op = expr.getKind() == AffineExprKind::Mod ? SLAP_QUASI_MOD
+                                                     : SLAP_QUASI_FLOOR_DIV;
+    ctx.terms.push_back(slap_quasi_term_t{
+        .coefficient = scale,
+        .op = op,
+        .operand = operand.build(),
+        .divisor = divisor.getValue(),
+    });
+    return true;
+  }
+  case AffineExprKind::SymbolId:
   case AffineExprKind::CeilDiv:
-    llvm_unreachable("mod, floordiv, ceildiv, symbols are not supported");
+    return false;
   }
+  return false;
+}
+
+// Lower `expr` over `operands`. An expression that cannot be represented is
+// reported through `ctx`, and the extraction fails once the walk is done.
+slap_expr_t extractAffineExpr(AffineExpr expr, OperandRange operands,
+                              ExtractContext &ctx) {
+  auto affine_ctx = newAffineContext(ctx, operands);
+  if (!extractAffineExpr(expr, 1, affine_ctx))
+    ctx.reportUnsupportedExpr();
+  return affine_ctx.build();
 }
 
 slap_expr_t extractAffineExpr(affine::AffineBound expr, ExtractContext &ctx) {
   auto map = expr.getMap();
   if (map.getNumResults() != 1)
     llvm_unreachable("only single result affine map is supported");
-  auto result = map.getResult(0);
-  auto affine_ctx = AffineContext{
-      .ext_ctx = ctx,
-      .parent = std::nullopt,
-      .coeff = llvm::SmallVector<ssize_t>(ctx.getNumOfIvars(), 0),
-      .bias = 0,
-      .operands = expr.getOperands(),
-  };
-  extractAffineExpr(result, affine_ctx);
-  return slap_expr_new(ctx.getSLAPContext(), affine_ctx.coeff.data(),
-                       affine_ctx.coeff.size(), affine_ctx.bias);
+  return extractAffineExpr(map.getResult(0), expr.getOperands(), ctx);
 }
 
 slap_graph_t extractOperation(Operation *op, ExtractContext &ctx);
 slap_graph_t extractFromLoop(affine::AffineForOp loop, ExtractContext &ctx);
 
 slap_graph_t extractAffineAccess(Value memref, AffineMap map,
-                                 OperandRange operands, Operation *next_node,
-                                 MLIRContext *mctx, ExtractContext &ctx) {
+                                 OperandRange operands, slap_access_kind kind,
+                                 Operation *next_node, MLIRContext *mctx,
+                                 ExtractContext &ctx) {
   if (auto memTy = dyn_cast<MemRefType>(memref.getType())) {
     if (!memTy.hasStaticShape())
       llvm_unreachable("dynamic shape memref is not supported");
     auto layout = memTy.getLayout();
     if (layout.isIdentity()) {
       auto shape = memTy.getShape();
-      auto exprs = map.getResults();
-      auto zip = llvm::zip(shape, exprs);
-      auto acc = std::accumulate(
-          zip.begin(), zip.end(), getAffineConstantExpr(0, mctx),
-          [](AffineExpr acc, std::tuple<int64_t, AffineExpr> pair) {
-            auto [size, expr] = pair;
-            return acc * size + expr;
-          });
-      auto affine_ctx = AffineContext{
-          .ext_ctx = ctx,
-          .parent = std::nullopt,
-          .coeff = llvm::SmallVector<ssize_t>(ctx.getNumOfIvars(), 0),
-          .bias = 0,
-          .operands = operands,
-      };
-      acc = acc * (memTy.getElementTypeBitWidth() / 8);
-      acc = simplifyAffineExpr(acc, map.getNumDims(), map.getNumSymbols());
-      extractAffineExpr(acc, affine_ctx);
-      auto expr = slap_expr_new(ctx.getSLAPContext(), affine_ctx.coeff.data(),
-                                affine_ctx.coeff.size(), affine_ctx.bias);
+      // Row-major byte strides: the last dimension is contiguous.
+      llvm::SmallVector<ssize_t> strides(shape.size());
+      ssize_t stride = memTy.getElementTypeBitWidth() / 8;
+      for (size_t i = shape.size(); i-- > 0;) {
+        strides[i] = stride;
+        stride *= shape[i];
+      }
+      llvm::SmallVector<slap_expr_t> subscripts;
+      for (auto result : map.getResults()) {
+        result = simplifyAffineExpr(result, map.getNumDims(),
+                                    map.getNumSymbols());
+        subscripts.push_back(extractAffineExpr(result, operands, ctx));
+      }
       auto memref_id = ctx.getMemRef(memref);
       auto next = extractOperation(next_node, ctx);
-      return slap_graph_new_access(ctx.getSLAPContext(), memref_id.id, expr,
-                                   next);
+      return slap_graph_new_access_nd(ctx.getSLAPContext(), memref_id.id,
+                                      subscripts.data(), strides.data(),
+                                      subscripts.size(), kind, next);
     } else if (auto strided = dyn_cast<StridedLayoutAttr>(layout)) {
       //   if (!strided.hasStaticLayout())
       //     llvm_unreachable("dynamic layout is not supported");
@@ -261,7 +281,8 @@ slap_graph_t extractAffineAccess(affine::AffineLoadOp load,
                                  ExtractContext &ctx) {
   auto g = extractAffineAccess(load.getMemRef(), load.getAffineMap(),
                                load->getOperands().drop_front(),
-                               load->getNextNode(), load.getContext(), ctx);
+                               SLAP_ACCESS_READ, load->getNextNode(),
+                               load.getContext(), ctx);
   if (slap_dump_node_of_affine_access(ctx.getSLAPContext()))
     load->setAttr(
         "slap.node",
@@ -274,7 +295,8 @@ slap_graph_t extractAffineAccess(affine::AffineStoreOp store,
                                  ExtractContext &ctx) {
   auto g = extractAffineAccess(store.getMemRef(), store.getAffineMap(),
                                store.getOperands().drop_front(2),
-                               store->getNextNode(), store.getContext(), ctx);
+                               SLAP_ACCESS_WRITE, store->getNextNode(),
+                               store.getContext(), ctx);
   if (slap_dump_node_of_affine_access(ctx.getSLAPContext()))
     store->setAttr(
         "slap.node",
@@ -341,9 +363,14 @@ slap_graph_t extractFromLoop(affine::AffineForOp loop, ExtractContext &ctx) {
   return lb_graph;
 }
 slap_graph_t extractFromEntry(affine::AffineForOp entry, slap_context_t ctx,
-                              size_t **vaddr, size_t *vaddr_len) {
+                              size_t **vaddr, size_t *vaddr_len,
+                              slap_extract_error *error) {
   ExtractContext extract_ctx{ctx, entry};
   auto loop = extractFromLoop(entry, extract_ctx);
+  if (extract_ctx.hasUnsupportedExpr()) {
+    *error = SLAP_EXTRACT_UNSUPPORTED_EXPR;
+    return nullptr;
+  }
   auto start = slap_graph_new_start(ctx, loop);
   *vaddr = slap_allocate_index_array(ctx, extract_ctx.getNumOfMemRefs());
   *vaddr_len = extract_ctx.getNumOfMemRefs();
@@ -366,7 +393,8 @@ slap_graph_t extractFromEntry(affine::AffineForOp entry, slap_context_t ctx,
 extern "C" {
 slap_graph_t slap_extract_affine_loop(slap_context_t ctx, char *path,
                                       size_t length, size_t **vaddr,
-                                      size_t *vaddr_len) {
+                                      size_t *vaddr_len,
+                                      slap_extract_error *error) {
   using namespace mlir;
   using namespace llvm;
   MLIRContext context;
@@ -374,14 +402,18 @@ slap_graph_t slap_extract_affine_loop(slap_context_t ctx, char *path,
   llvm::StringRef filepath(path, length);
   ErrorOr<std::unique_ptr<MemoryBuffer>> buffer =
       MemoryBuffer::getFile(filepath);
-  if (!buffer)
+  if (!buffer) {
+    *error = SLAP_EXTRACT_FILE_NOT_FOUND;
     return nullptr;
+  }
   SourceMgr source_mgr;
   source_mgr.AddNewSourceBuffer(std::move(*buffer), SMLoc());
   OwningOpRef<ModuleOp> module =
       parseSourceFile<ModuleOp>(source_mgr, &context);
-  if (!module)
+  if (!module) {
+    *error = SLAP_EXTRACT_PARSE_ERROR;
     return nullptr;
+  }
   affine::AffineForOp entry;
   module->walk([&entry](affine::AffineForOp forOp) {
     if (forOp->hasAttr("slap.extract")) {
@@ -391,9 +423,12 @@ slap_graph_t slap_extract_affine_loop(slap_context_t ctx, char *path,
     return WalkResult::advance();
   });
 
-  if (!entry)
+  if (!entry) {
+    *error = SLAP_EXTRACT_NO_AFFINE_LOOP;
     return nullptr;
+  }
 
-  return extractFromEntry(entry, ctx, vaddr, vaddr_len);
+  *error = SLAP_EXTRACT_OK;
+  return extractFromEntry(entry, ctx, vaddr, vaddr_len, error);
 }
 }
@@ -1,16 +1,30 @@
-use std::{cell::UnsafeCell, collections::HashSet, ptr::NonNull};
+use std::{
+    cell::UnsafeCell,
+    collections::{BTreeSet, HashSet},
+    hash::{Hash, Hasher},
+    ptr::NonNull,
+};
 
-use rustc_hash::FxHashMap;
+use rustc_hash::{FxHashMap, FxHashSet};
 
-use crate::{affine::Expr, simulator::SimulationCtx, Context};
+use crate::{
+    affine::{Expr, Subscripts},
+    simulator::SimulationCtx,
+    Context,
+};
 
 #[derive(Clone)]
 pub enum Graph<'a> {
     Start(Option<&'a Self>),
     End,
+    /// `offset` is always the linear byte offset into the memref. Accesses
+    /// built from per-dimension indices also keep them in `subscripts`;
+    /// `offset` is then their linearization.
     Access {
         memref: usize,
         offset: &'a Expr<'a>,
+        subscripts: Option<&'a Subscripts<'a>>,
+        kind: AccessKind,
         next: Option<&'a Self>,
     },
     Update {
@@ -26,7 +40,72 @@ pub enum Graph<'a> {
     },
 }
 
+/*
+typedef enum : int {
+  SLAP_ACCESS_READ,
+  SLAP_ACCESS_WRITE,
+  SLAP_ACCESS_READ_WRITE,
+} slap_access_kind;
+*/
+
+#[repr(i32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum AccessKind {
+    Read = 0,
+    Write = 1,
+    ReadWrite = 2,
+}
+
+impl AccessKind {
+    pub fn is_read(self) -> bool {
+        matches!(self, AccessKind::Read | AccessKind::ReadWrite)
+    }
+    pub fn is_write(self) -> bool {
+        matches!(self, AccessKind::Write | AccessKind::ReadWrite)
+    }
+}
+
 impl<'a> Graph<'a> {
+    pub(crate) fn edges(&self) -> (Option<&'a Self>, Option<&'a Self>) {
+        match *self {
+            Graph::Start(next) => (next, None),
+            Graph::End => (None, None),
+            Graph::Access { next, .. } | Graph::Update { next, .. } => (next, None),
+            Graph::Branch { then, r#else, .. } => (then, r#else),
+        }
+    }
+    /// Direct successors of this node: `next`, or `then` followed by `else`.
+    pub fn successors(&self) -> impl Iterator<Item = &'a Self> {
+        let (first, second) = self.edges();
+        first.into_iter().chain(second)
+    }
+    /// Map every reachable node to the distinct nodes with an edge into it.
+    /// Nodes without predecessors (the root) have no entry.
+    pub fn build_predecessor_map(
+        &'a self,
+    ) -> FxHashMap<NonNull<Graph<'a>>, Vec<NonNull<Graph<'a>>>> {
+        let mut result = FxHashMap::<_, Vec<_>>::default();
+        for node in self.iter() {
+            let (first, second) = node.edges();
+            let second = second.filter(|x| !first.is_some_and(|y| std::ptr::eq(*x, y)));
+            for succ in first.into_iter().chain(second) {
+                result
+                    .entry(NonNull::from(succ))
+                    .or_default()
+                    .push(NonNull::from(node));
+            }
+        }
+        result
+    }
+    /// Iterate over every node reachable from `self` in preorder, visiting
+    /// shared subgraphs and loop headers only once.
+    pub fn iter(&'a self) -> GraphIter<'a> {
+        GraphIter {
+            stack: vec![self],
+            visited: FxHashSet::default(),
+        }
+    }
     fn populate_adjacency(&self, result: &mut FxHashMap<usize, Box<[usize]>>) {
         let token = self as *const _ as usize;
         match result.entry(token) {
@@ -75,22 +154,182 @@ impl<'a> Graph<'a> {
         self.populate_adjacency(&mut result);
         result
     }
+    /// Hash of the reachable node set and its edges, identifying nodes by
+    /// address. Any rewiring through the `slap_graph_*_set_*` setters changes
+    /// the fingerprint.
+    pub fn fingerprint(&self) -> u64 {
+        let mut edges = self.adjacency().into_iter().collect::<Vec<_>>();
+        edges.sort_unstable();
+        let mut hasher = rustc_hash::FxHasher::default();
+        edges.hash(&mut hasher);
+        hasher.finish()
+    }
+    /// Whether two nodes have the same kind and payload, ignoring edges.
+    fn same_label(&self, other: &Graph<'_>) -> bool {
+        match (self, other) {
+            (Graph::Start(_), Graph::Start(_)) | (Graph::End, Graph::End) => true,
+            (
+                Graph::Access {
+                    memref: m1,
+                    offset: o1,
+                    subscripts: s1,
+                    kind: k1,
+                    ..
+                },
+                Graph::Access {
+                    memref: m2,
+                    offset: o2,
+                    subscripts: s2,
+                    kind: k2,
+                    ..
+                },
+            ) => m1 == m2 && o1 == o2 && s1 == s2 && k1 == k2,
+            (
+                Graph::Update {
+                    ivar: v1, expr: e1, ..
+                },
+                Graph::Update {
+                    ivar: v2, expr: e2, ..
+                },
+            )
+            | (
+                Graph::Branch {
+                    ivar: v1,
+                    bound: e1,
+                    ..
+                },
+                Graph::Branch {
+                    ivar: v2,
+                    bound: e2,
+                    ..
+                },
+            ) => v1 == v2 && e1 == e2,
+            _ => false,
+        }
+    }
+    /// Whether `self` and `other` are the same graph up to the arenas they
+    /// live in: nodes correspond one-to-one with equal payloads and edges,
+    /// including the sharing of successors and back-edges.
+    pub fn structurally_eq(&self, other: &Graph<'_>) -> bool {
+        let mut forward = FxHashMap::default();
+        let mut backward = FxHashMap::default();
+        let mut stack: Vec<(&Graph, &Graph)> = vec![(self, other)];
+        while let Some((a, b)) = stack.pop() {
+            let (pa, pb) = (a as *const _ as usize, b as *const _ as usize);
+            match (forward.get(&pa), backward.get(&pb)) {
+                (Some(x), Some(y)) if *x == pb && *y == pa => continue,
+                (None, None) => {
+                    forward.insert(pa, pb);
+                    backward.insert(pb, pa);
+                }
+                _ => return false,
+            }
+            if !a.same_label(b) {
+                return false;
+            }
+            let (a1, a2) = a.edges();
+            let (b1, b2) = b.edges();
+            for pair in [(a1, b1), (a2, b2)] {
+                match pair {
+                    (Some(x), Some(y)) => stack.push((x, y)),
+                    (None, None) => (),
+                    _ => return false,
+                }
+            }
+        }
+        true
+    }
+    /// Hash of the graph structure that does not depend on node addresses,
+    /// so structurally equal graphs in different arenas hash the same.
+    pub fn structural_hash(&'a self) -> u64 {
+        let ids = self
+            .iter()
+            .enumerate()
+            .map(|(id, node)| (NonNull::from(node), id))
+            .collect::<FxHashMap<_, _>>();
+        let id = |x: Option<&'a Self>| x.map(|x| ids[&NonNull::from(x)]);
+        let mut hasher = rustc_hash::FxHasher::default();
+        for node in self.iter() {
+            std::mem::discriminant(node).hash(&mut hasher);
+            match node {
+                Graph::Access {
+                    memref,
+                    offset,
+                    subscripts,
+                    kind,
+                    ..
+                } => (memref, offset, subscripts, kind).hash(&mut hasher),
+                Graph::Update { ivar, expr, .. }
+                | Graph::Branch {
+                    ivar, bound: expr, ..
+                } => (ivar, expr).hash(&mut hasher),
+                Graph::Start(_) | Graph::End => (),
+            }
+            let (first, second) = node.edges();
+            (id(first), id(second)).hash(&mut hasher);
+        }
+        hasher.finish()
+    }
     pub fn format(
         &self,
         writer: &mut std::fmt::Formatter<'_>,
         visited: &mut HashSet<NonNull<Self>>,
+    ) -> std::fmt::Result {
+        self.format_impl(
+            writer,
+            visited,
+            &FxHashSet::default(),
+            &mut FxHashMap::default(),
+        )
+    }
+    /// Like [`Graph::format`], but every node reached more than once is
+    /// labeled `#n` where it is first printed, and later visits print
+    /// `ref #n` instead of `...`. Labels count up in printing order, so they
+    /// are stable for a given graph.
+    pub fn format_labeled(&'a self, writer: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // Count edges rather than predecessors: a `Branch` whose sides meet
+        // prints its target twice.
+        let mut incoming = FxHashMap::<_, usize>::default();
+        for succ in self.iter().flat_map(Graph::successors) {
+            *incoming.entry(NonNull::from(succ)).or_default() += 1;
+        }
+        let shared = incoming
+            .into_iter()
+            .filter(|(node, edges)| *edges > 1 || std::ptr::eq(node.as_ptr(), self))
+            .map(|(node, _)| node)
+            .collect();
+        self.format_impl(
+            writer,
+            &mut HashSet::new(),
+            &shared,
+            &mut FxHashMap::default(),
+        )
+    }
+    fn format_impl(
+        &self,
+        writer: &mut std::fmt::Formatter<'_>,
+        visited: &mut HashSet<NonNull<Self>>,
+        shared: &FxHashSet<NonNull<Self>>,
+        labels: &mut FxHashMap<NonNull<Self>, usize>,
     ) -> std::fmt::Result {
         let token = NonNull::from(self);
         if visited.contains(&token) {
-            write!(writer, "...")?;
-            return Ok(());
+            return match labels.get(&token) {
+                Some(label) => write!(writer, "ref #{label}"),
+                None => write!(writer, "..."),
+            };
         }
         visited.insert(token);
+        if shared.contains(&token) {
+            let label = labels.len();
+            labels.insert(token, label);
+            write!(writer, "#{label} ")?;
+        }
         match self {
             Graph::Start(next) => {
                 write!(writer, "Start(")?;
                 if let Some(next) = next {
-                    next.format(writer, visited)?;
+                    next.format_impl(writer, visited, shared, labels)?;
                 }
                 write!(writer, ")")
             }
@@ -100,18 +339,20 @@ impl<'a> Graph<'a> {
             Graph::Access {
                 memref,
                 offset,
+                kind,
                 next,
+                ..
             } => {
-                write!(writer, "Access({}, {:?}, ", memref, offset)?;
+                write!(writer, "Access({}, {}, {:?}, ", memref, offset, kind)?;
                 if let Some(next) = next {
-                    next.format(writer, visited)?;
+                    next.format_impl(writer, visited, shared, labels)?;
                 }
                 write!(writer, ")")
             }
             Graph::Update { ivar, expr, next } => {
-                write!(writer, "Update({}, {:?}, ", ivar, expr)?;
+                write!(writer, "Update({}, {}, ", ivar, expr)?;
                 if let Some(next) = next {
-                    next.format(writer, visited)?;
+                    next.format_impl(writer, visited, shared, labels)?;
                 }
                 write!(writer, ")")
             }
@@ -121,13 +362,15 @@ impl<'a> Graph<'a> {
                 then,
                 r#else,
             } => {
-                write!(writer, "Branch({}, {:?}, ", ivar, bound)?;
-                if let Some(then) = then {
-                    then.format(writer, visited)?;
-                    write!(writer, ", ")?;
+                write!(writer, "Branch({}, {}, ", ivar, bound)?;
+                match then {
+                    Some(then) => then.format_impl(writer, visited, shared, labels)?,
+                    None => write!(writer, "None")?,
                 }
-                if let Some(r#else) = r#else {
-                    r#else.format(writer, visited)?;
+                write!(writer, ", ")?;
+                match r#else {
+                    Some(r#else) => r#else.format_impl(writer, visited, shared, labels)?,
+                    None => write!(writer, "None")?,
                 }
                 write!(writer, ")")
             }
@@ -224,9 +467,568 @@ impl<'a> Graph<'a> {
     }
 }
 
+/// Structural problem found by [`Graph::validate`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GraphDefect<'a> {
+    /// The root `Start` has no successor.
+    StartWithoutSuccessor,
+    /// An `Access` or `Update` has no `next`.
+    MissingNext(NonNull<Graph<'a>>),
+    /// A `Branch` has no `then` side.
+    BranchMissingThen(NonNull<Graph<'a>>),
+    /// A `Branch` has no `else` side.
+    BranchMissingElse(NonNull<Graph<'a>>),
+    /// No `End` node is reachable, so the walk can never finish.
+    UnreachableEnd,
+    /// A cycle through this node contains no `Branch`, so it can never exit.
+    CycleWithoutBranch(NonNull<Graph<'a>>),
+}
+
+impl<'a> Graph<'a> {
+    /// Check the graph for defects that make the native simulation crash or
+    /// hang: missing successors, no reachable `End`, and cycles that have no
+    /// `Branch` to leave them.
+    pub fn validate(&'a self) -> Result<(), Vec<GraphDefect<'a>>> {
+        let mut defects = Vec::new();
+        if let Graph::Start(None) = self {
+            defects.push(GraphDefect::StartWithoutSuccessor);
+        }
+        let mut has_end = false;
+        for node in self.iter() {
+            let ptr = NonNull::from(node);
+            match node {
+                Graph::End => has_end = true,
+                Graph::Access { next: None, .. } | Graph::Update { next: None, .. } => {
+                    defects.push(GraphDefect::MissingNext(ptr))
+                }
+                Graph::Branch { then, r#else, .. } => {
+                    if then.is_none() {
+                        defects.push(GraphDefect::BranchMissingThen(ptr));
+                    }
+                    if r#else.is_none() {
+                        defects.push(GraphDefect::BranchMissingElse(ptr));
+                    }
+                }
+                _ => (),
+            }
+        }
+        if !has_end {
+            defects.push(GraphDefect::UnreachableEnd);
+        }
+        // Non-branch nodes have at most one successor, so any branch-free
+        // cycle is found by following `next` chains.
+        let mut done = FxHashSet::default();
+        for node in self.iter() {
+            let mut path = FxHashSet::default();
+            let mut current = Some(node);
+            while let Some(node) = current {
+                let ptr = NonNull::from(node);
+                if matches!(node, Graph::Branch { .. }) || done.contains(&ptr) {
+                    break;
+                }
+                if !path.insert(ptr) {
+                    defects.push(GraphDefect::CycleWithoutBranch(ptr));
+                    break;
+                }
+                current = node.edges().0;
+            }
+            done.extend(path);
+        }
+        if defects.is_empty() {
+            Ok(())
+        } else {
+            Err(defects)
+        }
+    }
+}
+
+/// Overview of a graph computed by [`Graph::stats`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GraphStats {
+    pub access_nodes: usize,
+    pub update_nodes: usize,
+    pub branch_nodes: usize,
+    pub distinct_memrefs: BTreeSet<usize>,
+    /// Deepest nesting of the loops found by [`Graph::classify_branches`]:
+    /// a loop inside a loop has depth 2, two sibling loops depth 1.
+    pub max_loop_depth: usize,
+    pub has_cycles: bool,
+}
+
+impl<'a> Graph<'a> {
+    /// Count the nodes of each kind and the memrefs they touch.
+    pub fn stats(&'a self) -> GraphStats {
+        let mut stats = GraphStats::default();
+        for node in self.iter() {
+            match node {
+                Graph::Access { memref, .. } => {
+                    stats.access_nodes += 1;
+                    stats.distinct_memrefs.insert(*memref);
+                }
+                Graph::Update { .. } => stats.update_nodes += 1,
+                Graph::Branch { .. } => stats.branch_nodes += 1,
+                _ => (),
+            }
+        }
+        stats.has_cycles = self.longest_path(&|_| false).1;
+        stats.max_loop_depth = self.max_loop_depth();
+        stats
+    }
+    /// Number of loop bodies entered on the way to the deepest node. Each
+    /// node takes the depth it is first reached at, so a loop header has
+    /// the depth of the code around the loop and its body one more.
+    fn max_loop_depth(&'a self) -> usize {
+        let kinds = self.classify_branches();
+        let mut visited = FxHashSet::default();
+        let mut stack = vec![(self, 0)];
+        let mut max_depth = 0;
+        while let Some((node, depth)) = stack.pop() {
+            if !visited.insert(NonNull::from(node)) {
+                continue;
+            }
+            max_depth = max_depth.max(depth);
+            let (first, second) = node.edges();
+            let (first_depth, second_depth) = match kinds.get(&NonNull::from(node)) {
+                Some(BranchKind::Loop {
+                    body_side: BranchSide::Then,
+                    ..
+                }) => (depth + 1, depth),
+                Some(BranchKind::Loop {
+                    body_side: BranchSide::Else,
+                    ..
+                }) => (depth, depth + 1),
+                _ => (depth, depth),
+            };
+            stack.extend(second.map(|x| (x, second_depth)));
+            stack.extend(first.map(|x| (x, first_depth)));
+        }
+        max_depth
+    }
+    /// Largest number of `Access` nodes on a path from the root, with edges
+    /// back to a node already on the path (loop back-edges) cut.
+    pub fn longest_access_chain(&'a self) -> usize {
+        self.longest_path(&|x| matches!(x, Graph::Access { .. })).0
+    }
+    /// Largest number of nodes matching `counted` on a path from the root
+    /// that does not follow a back-edge, and whether any back-edge exists.
+    fn longest_path(&'a self, counted: &impl Fn(&Graph) -> bool) -> (usize, bool) {
+        let mut has_cycles = false;
+        let length = self.longest_path_impl(
+            counted,
+            &mut FxHashSet::default(),
+            &mut FxHashMap::default(),
+            &mut has_cycles,
+        );
+        (length, has_cycles)
+    }
+    fn longest_path_impl(
+        &'a self,
+        counted: &impl Fn(&Graph) -> bool,
+        on_path: &mut FxHashSet<NonNull<Graph<'a>>>,
+        memo: &mut FxHashMap<NonNull<Graph<'a>>, usize>,
+        has_cycles: &mut bool,
+    ) -> usize {
+        let ptr = NonNull::from(self);
+        if let Some(length) = memo.get(&ptr) {
+            return *length;
+        }
+        if !on_path.insert(ptr) {
+            *has_cycles = true;
+            return 0;
+        }
+        let below = self
+            .successors()
+            .map(|x| x.longest_path_impl(counted, on_path, memo, has_cycles))
+            .max()
+            .unwrap_or(0);
+        let result = below + counted(self) as usize;
+        on_path.remove(&ptr);
+        memo.insert(ptr, result);
+        result
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BranchSide {
+    Then,
+    Else,
+}
+
+/// Role of a `Branch`, see [`Graph::classify_branches`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BranchKind {
+    /// A loop header: `body_side` leads back to the branch, `exit_side`
+    /// leaves the loop.
+    Loop {
+        body_side: BranchSide,
+        exit_side: BranchSide,
+    },
+    Conditional,
+}
+
+impl<'a> Graph<'a> {
+    /// Tell loop headers from plain conditionals. A depth-first walk finds
+    /// every back-edge; the first `Branch` on the path from the edge's target
+    /// is the loop header, and the side it took on the path is the loop body.
+    pub fn classify_branches(&'a self) -> FxHashMap<NonNull<Graph<'a>>, BranchKind> {
+        let mut result = self
+            .iter()
+            .filter(|x| matches!(x, Graph::Branch { .. }))
+            .map(|x| (NonNull::from(x), BranchKind::Conditional))
+            .collect();
+        self.classify_branches_impl(
+            &mut Vec::new(),
+            &mut FxHashMap::default(),
+            &mut FxHashSet::default(),
+            &mut result,
+        );
+        result
+    }
+    fn classify_branches_impl(
+        &'a self,
+        path: &mut Vec<(NonNull<Graph<'a>>, Option<BranchSide>)>,
+        on_path: &mut FxHashMap<NonNull<Graph<'a>>, usize>,
+        done: &mut FxHashSet<NonNull<Graph<'a>>>,
+        result: &mut FxHashMap<NonNull<Graph<'a>>, BranchKind>,
+    ) {
+        let ptr = NonNull::from(self);
+        if let Some(start) = on_path.get(&ptr).copied() {
+            let header = path[start..]
+                .iter()
+                .find_map(|(node, side)| Some((*node, (*side)?)));
+            if let Some((header, body_side)) = header {
+                let exit_side = match body_side {
+                    BranchSide::Then => BranchSide::Else,
+                    BranchSide::Else => BranchSide::Then,
+                };
+                result.insert(
+                    header,
+                    BranchKind::Loop {
+                        body_side,
+                        exit_side,
+                    },
+                );
+            }
+            return;
+        }
+        if !done.insert(ptr) {
+            return;
+        }
+        on_path.insert(ptr, path.len());
+        path.push((ptr, None));
+        let (first, second) = self.edges();
+        let is_branch = matches!(self, Graph::Branch { .. });
+        for (succ, side) in [(first, BranchSide::Then), (second, BranchSide::Else)] {
+            if let Some(succ) = succ {
+                path.last_mut().unwrap().1 = is_branch.then_some(side);
+                succ.classify_branches_impl(path, on_path, done, result);
+            }
+        }
+        path.pop();
+        on_path.remove(&ptr);
+    }
+}
+
+/// One difference found by [`Graph::diff`].
+#[derive(Debug, Clone, Copy)]
+pub enum GraphDiff<'a, 'b> {
+    /// An access of the new graph with no counterpart in the old one.
+    AddedAccess(&'b Graph<'b>),
+    /// An access of the old graph with no counterpart in the new one.
+    RemovedAccess(&'a Graph<'a>),
+    /// Nodes at the same position whose variant or payload differs, e.g. an
+    /// access with another offset or a branch with another bound.
+    Changed {
+        old: &'a Graph<'a>,
+        new: &'b Graph<'b>,
+    },
+    /// Matching nodes whose edge `edge` (`0` for `next` or `then`, `1` for
+    /// `else`) leads to nodes that do not correspond to each other.
+    Retargeted {
+        old: &'a Graph<'a>,
+        new: &'b Graph<'b>,
+        edge: usize,
+    },
+}
+
+impl<'a> Graph<'a> {
+    /// Walk `self` and `other` in lockstep like [`Graph::structurally_eq`],
+    /// collecting the differences instead of stopping at the first one.
+    ///
+    /// Nodes are matched by their position in the walk. An `Access` facing a
+    /// node of another variant is taken as inserted or removed, and the walk
+    /// continues past it on that side only. So is an access facing a
+    /// different access when the other side's successor matches it, so that
+    /// a single inserted access shows up as just that. Accesses never matched
+    /// are reported as added or removed at the end.
+    pub fn diff<'b>(&'a self, other: &'b Graph<'b>) -> Vec<GraphDiff<'a, 'b>> {
+        let mut result = Vec::new();
+        let mut forward = FxHashMap::default();
+        let mut backward = FxHashMap::default();
+        let mut skipped_old = FxHashSet::default();
+        let mut skipped_new = FxHashSet::default();
+        type Parent<'a, 'b> = Option<(&'a Graph<'a>, &'b Graph<'b>, usize)>;
+        let mut stack: Vec<(&'a Graph<'a>, &'b Graph<'b>, Parent<'a, 'b>)> =
+            vec![(self, other, None)];
+        while let Some((a, b, parent)) = stack.pop() {
+            let (pa, pb) = (NonNull::from(a), NonNull::from(b));
+            match (a, b) {
+                (Graph::Access { next, .. }, _) if !matches!(b, Graph::Access { .. }) => {
+                    if skipped_old.insert(pa) {
+                        result.push(GraphDiff::RemovedAccess(a));
+                        if let Some(next) = next {
+                            stack.push((next, b, parent));
+                        }
+                    }
+                    continue;
+                }
+                (_, Graph::Access { next, .. }) if !matches!(a, Graph::Access { .. }) => {
+                    if skipped_new.insert(pb) {
+                        result.push(GraphDiff::AddedAccess(b));
+                        if let Some(next) = next {
+                            stack.push((a, next, parent));
+                        }
+                    }
+                    continue;
+                }
+                (Graph::Access { next: a_next, .. }, Graph::Access { next: b_next, .. })
+                    if !a.same_label(b)
+                        && !forward.contains_key(&pa)
+                        && !backward.contains_key(&pb) =>
+                {
+                    if let Some(b_next) = b_next.filter(|x| a.same_label(x)) {
+                        if skipped_new.insert(pb) {
+                            result.push(GraphDiff::AddedAccess(b));
+                            stack.push((a, b_next, parent));
+                        }
+                        continue;
+                    }
+                    if let Some(a_next) = a_next.filter(|x| x.same_label(b)) {
+                        if skipped_old.insert(pa) {
+                            result.push(GraphDiff::RemovedAccess(a));
+                            stack.push((a_next, b, parent));
+                        }
+                        continue;
+                    }
+                }
+                _ => (),
+            }
+            match (forward.get(&pa), backward.get(&pb)) {
+                (Some(x), Some(y)) if *x == pb && *y == pa => continue,
+                (None, None) => {
+                    forward.insert(pa, pb);
+                    backward.insert(pb, pa);
+                }
+                _ => {
+                    if let Some((old, new, edge)) = parent {
+                        result.push(GraphDiff::Retargeted { old, new, edge });
+                    }
+                    continue;
+                }
+            }
+            if !a.same_label(b) {
+                result.push(GraphDiff::Changed { old: a, new: b });
+            }
+            let (a1, a2) = a.edges();
+            let (b1, b2) = b.edges();
+            for (edge, pair) in [(a1, b1), (a2, b2)].into_iter().enumerate().rev() {
+                match pair {
+                    (Some(x), Some(y)) => stack.push((x, y, Some((a, b, edge)))),
+                    (None, None) => (),
+                    _ => result.push(GraphDiff::Retargeted {
+                        old: a,
+                        new: b,
+                        edge,
+                    }),
+                }
+            }
+        }
+        let is_access = |x: &&Graph| matches!(x, Graph::Access { .. });
+        for node in self.iter().filter(is_access) {
+            let ptr = NonNull::from(node);
+            if !forward.contains_key(&ptr) && !skipped_old.contains(&ptr) {
+                result.push(GraphDiff::RemovedAccess(node));
+            }
+        }
+        for node in other.iter().filter(is_access) {
+            let ptr = NonNull::from(node);
+            if !backward.contains_key(&ptr) && !skipped_new.contains(&ptr) {
+                result.push(GraphDiff::AddedAccess(node));
+            }
+        }
+        result
+    }
+}
+
+impl<'a> Graph<'a> {
+    /// Memrefs accessed from `start` onwards, for the footprint of one loop
+    /// level. If `start` is a loop header, only its body is walked, so the
+    /// walk ends where the body returns to the header; otherwise everything
+    /// reachable from `start` counts. Nodes already walked, including targets
+    /// of back-edges, are not descended into again. Empty if `start` is not
+    /// part of the graph rooted at `self`.
+    pub fn reachable_memrefs_from(&'a self, start: &'a Graph<'a>) -> BTreeSet<usize> {
+        if !self.iter().any(|x| std::ptr::eq(x, start)) {
+            return BTreeSet::new();
+        }
+        Self::region(start, &self.classify_branches())
+            .into_iter()
+            .filter_map(|x| match x {
+                Graph::Access { memref, .. } => Some(*memref),
+                _ => None,
+            })
+            .collect()
+    }
+    /// Accesses in the body of the loop on `ivar`, see
+    /// [`Graph::find_in_loop`].
+    pub fn accesses_in_loop(&'a self, ivar: usize) -> Vec<&'a Graph<'a>> {
+        self.find_in_loop(ivar, |x| matches!(x, Graph::Access { .. }))
+    }
+    /// Nodes matching `predicate` in the body of every loop whose header
+    /// branches on `ivar`, nested loops included, in the order of
+    /// [`Graph::iter`]. Loops are found by [`Graph::classify_branches`]; the
+    /// header itself and the `Update` that enters the loop are outside the
+    /// body, the step `Update` is inside.
+    pub fn find_in_loop(
+        &'a self,
+        ivar: usize,
+        predicate: impl Fn(&Graph<'a>) -> bool,
+    ) -> Vec<&'a Graph<'a>> {
+        let kinds = self.classify_branches();
+        let mut body = FxHashSet::default();
+        for node in self.iter() {
+            let is_header = matches!(node, Graph::Branch { ivar: x, .. } if *x == ivar)
+                && matches!(
+                    kinds.get(&NonNull::from(node)),
+                    Some(BranchKind::Loop { .. })
+                );
+            if is_header {
+                body.extend(
+                    Self::region(node, &kinds)[1..]
+                        .iter()
+                        .map(|x| NonNull::from(*x)),
+                );
+            }
+        }
+        self.iter()
+            .filter(|x| body.contains(&NonNull::from(*x)) && predicate(x))
+            .collect()
+    }
+    /// `start` followed by the nodes reachable from it. If `start` is a loop
+    /// header, only its body is walked, so the walk ends where the body
+    /// returns to the header.
+    fn region(
+        start: &'a Graph<'a>,
+        kinds: &FxHashMap<NonNull<Graph<'a>>, BranchKind>,
+    ) -> Vec<&'a Graph<'a>> {
+        let mut visited = FxHashSet::default();
+        visited.insert(NonNull::from(start));
+        let mut stack = match (start, kinds.get(&NonNull::from(start))) {
+            (Graph::Branch { then, r#else, .. }, Some(BranchKind::Loop { body_side, .. })) => {
+                match body_side {
+                    BranchSide::Then => then.iter().copied().collect(),
+                    BranchSide::Else => r#else.iter().copied().collect(),
+                }
+            }
+            _ => start.successors().collect::<Vec<_>>(),
+        };
+        let mut result = vec![start];
+        while let Some(node) = stack.pop() {
+            if !visited.insert(NonNull::from(node)) {
+                continue;
+            }
+            result.push(node);
+            stack.extend(node.successors());
+        }
+        result
+    }
+}
+
+impl<'a> Graph<'a> {
+    /// Statically estimate how many times each loop body runs, keyed by the
+    /// loop's induction variable.
+    ///
+    /// A loop is a `Branch` reached from an initial `Update` of its ivar and
+    /// from a step `Update` of the form `ivar + step`, as produced by the
+    /// extractor. The count is `None` if the initial value or the bound is
+    /// not a constant, or the step is not a positive constant.
+    pub fn estimate_trip_counts(&'a self) -> FxHashMap<usize, Option<u64>> {
+        let mut updates = FxHashMap::<NonNull<Graph<'a>>, Vec<(usize, &Expr)>>::default();
+        for node in self.iter() {
+            if let Graph::Update {
+                ivar,
+                expr,
+                next: Some(next),
+            } = node
+            {
+                updates
+                    .entry(NonNull::from(*next))
+                    .or_default()
+                    .push((*ivar, expr));
+            }
+        }
+        let mut result = FxHashMap::default();
+        for node in self.iter() {
+            let Graph::Branch { ivar, bound, .. } = node else {
+                continue;
+            };
+            let mut init = None;
+            let mut step = None;
+            for (target, expr) in updates.get(&NonNull::from(node)).into_iter().flatten() {
+                if target != ivar {
+                    continue;
+                }
+                let coefficients = expr.coefficients();
+                let self_step = expr.is_affine()
+                    && coefficients.get(*ivar) == Some(&1)
+                    && coefficients
+                        .iter()
+                        .enumerate()
+                        .all(|(i, x)| i == *ivar || *x == 0);
+                if self_step {
+                    step = Some(expr.bias());
+                } else if let Some(x) = expr.constant() {
+                    init = Some(x);
+                }
+            }
+            let count = match (init, step, bound.constant()) {
+                (Some(init), Some(step), Some(bound)) if step > 0 => {
+                    let span = (bound - init).max(0) as u64;
+                    Some(span.div_ceil(step as u64))
+                }
+                _ => None,
+            };
+            result.insert(*ivar, count);
+        }
+        result
+    }
+}
+
+pub struct GraphIter<'a> {
+    stack: Vec<&'a Graph<'a>>,
+    visited: FxHashSet<NonNull<Graph<'a>>>,
+}
+
+impl<'a> Iterator for GraphIter<'a> {
+    type Item = &'a Graph<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let node = self.stack.pop()?;
+            if !self.visited.insert(NonNull::from(node)) {
+                continue;
+            }
+            let (first, second) = node.edges();
+            self.stack.extend(second);
+            self.stack.extend(first);
+            return Some(node);
+        }
+    }
+}
+
 impl std::fmt::Debug for Graph<'_> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        self.format(f, &mut HashSet::new())
+        self.format_labeled(f)
     }
 }
 
@@ -243,8 +1045,8 @@ pub unsafe extern "C" fn slap_graph_new_start(
 }
 
 #[no_mangle]
-pub extern "C" fn slap_graph_new_end<'a>(ctx: *const Context) -> *mut Graph<'a> {
-    let ctx = unsafe { &*ctx };
+pub unsafe extern "C" fn slap_graph_new_end<'a>(ctx: *const Context) -> *mut Graph<'a> {
+    let ctx = &*ctx;
     ctx.arena.alloc(UnsafeCell::new(Graph::End)).get_mut()
 }
 
@@ -253,6 +1055,7 @@ pub unsafe extern "C" fn slap_graph_new_access<'a>(
     ctx: *const Context,
     memref: usize,
     offset: *mut Expr<'a>,
+    kind: AccessKind,
     next: *mut Graph<'a>,
 ) -> *mut Graph<'a> {
     let ctx = &*ctx;
@@ -260,6 +1063,47 @@ pub unsafe extern "C" fn slap_graph_new_access<'a>(
         .alloc(UnsafeCell::new(Graph::Access {
             memref,
             offset: &*offset,
+            subscripts: None,
+            kind,
+            next: NonNull::new(next).map(|ptr| ptr.as_ref()),
+        }))
+        .get_mut()
+}
+
+/*
+slap_graph_t slap_graph_new_access_nd(slap_context_t, size_t memref,
+                                      slap_expr_t *subscripts,
+                                      const ssize_t *strides, size_t rank,
+                                      slap_access_kind kind, slap_graph_t next);
+*/
+
+#[no_mangle]
+pub unsafe extern "C" fn slap_graph_new_access_nd<'a>(
+    ctx: *const Context,
+    memref: usize,
+    subscripts: *const *mut Expr<'a>,
+    strides: *const isize,
+    rank: usize,
+    kind: AccessKind,
+    next: *mut Graph<'a>,
+) -> *mut Graph<'a> {
+    let ctx = &*ctx;
+    let indices = std::slice::from_raw_parts(subscripts, rank)
+        .iter()
+        .map(|x| &**x)
+        .collect::<Vec<_>>();
+    let strides = std::slice::from_raw_parts(strides, rank);
+    // Both slices have `rank` entries, so the subscripts always match.
+    let Some(subscripts) = Subscripts::new(ctx, &indices, strides) else {
+        return std::ptr::null_mut();
+    };
+    let subscripts = &*ctx.arena.alloc(subscripts);
+    ctx.arena
+        .alloc(UnsafeCell::new(Graph::Access {
+            memref,
+            offset: ctx.arena.alloc(subscripts.linearize(ctx)),
+            subscripts: Some(subscripts),
+            kind,
             next: NonNull::new(next).map(|ptr| ptr.as_ref()),
         }))
         .get_mut()
@@ -309,25 +1153,88 @@ extern "C" {
         length: usize,
         vaddr: *mut *const usize,
         vaddr_len: *mut usize,
+        error: *mut i32,
     ) -> Option<NonNull<Graph<'a>>>;
 }
 
+/*
+typedef enum : int {
+  SLAP_EXTRACT_OK,
+  SLAP_EXTRACT_FILE_NOT_FOUND,
+  SLAP_EXTRACT_PARSE_ERROR,
+  SLAP_EXTRACT_NO_AFFINE_LOOP,
+  SLAP_EXTRACT_UNSUPPORTED_EXPR,
+} slap_extract_error;
+*/
+
+const SLAP_EXTRACT_FILE_NOT_FOUND: i32 = 1;
+const SLAP_EXTRACT_NO_AFFINE_LOOP: i32 = 3;
+const SLAP_EXTRACT_UNSUPPORTED_EXPR: i32 = 4;
+
+#[derive(Debug)]
+pub enum ExtractError {
+    /// The path contains an interior NUL byte and cannot be passed to C.
+    InvalidPath(std::ffi::NulError),
+    /// The file could not be opened.
+    FileNotFound,
+    /// The file is not a valid MLIR module.
+    ParseError,
+    /// No `affine.for` carrying the `slap.extract` attribute was found.
+    NoAffineLoop,
+    /// A bound or subscript uses `ceildiv`, a symbol or a product of
+    /// non-constant terms, which an [`Expr`] cannot represent.
+    UnsupportedExpr,
+}
+
+impl std::fmt::Display for ExtractError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ExtractError::InvalidPath(err) => write!(f, "invalid path: {err}"),
+            ExtractError::FileNotFound => write!(f, "file not found"),
+            ExtractError::ParseError => write!(f, "failed to parse mlir"),
+            ExtractError::NoAffineLoop => write!(f, "no affine loop marked with slap.extract"),
+            ExtractError::UnsupportedExpr => write!(f, "unsupported affine expression"),
+        }
+    }
+}
+
+impl std::error::Error for ExtractError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ExtractError::InvalidPath(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
 impl<'a> Graph<'a> {
-    pub fn new_from_file(ctx: &'a Context, filename: &str) -> Option<(&'a Self, &'a [usize])> {
-        let filename = std::ffi::CString::new(filename).unwrap();
+    pub fn new_from_file(
+        ctx: &'a Context,
+        filename: &str,
+    ) -> Result<(&'a Self, &'a [usize]), ExtractError> {
+        let filename = std::ffi::CString::new(filename).map_err(ExtractError::InvalidPath)?;
         unsafe {
             let mut vaddr_cell = std::ptr::null();
             let mut vaddr_len = 0;
+            let mut error = 0;
             let graph = slap_extract_affine_loop(
                 ctx,
                 filename.as_ptr(),
                 filename.as_bytes().len(),
                 &mut vaddr_cell as _,
                 &mut vaddr_len,
+                &mut error,
             );
-            graph
-                .map(|graph| graph.as_ref())
-                .map(|graph| (graph, std::slice::from_raw_parts(vaddr_cell, vaddr_len)))
+            match (graph, error) {
+                (Some(graph), _) => Ok((
+                    graph.as_ref(),
+                    std::slice::from_raw_parts(vaddr_cell, vaddr_len),
+                )),
+                (None, SLAP_EXTRACT_FILE_NOT_FOUND) => Err(ExtractError::FileNotFound),
+                (None, SLAP_EXTRACT_NO_AFFINE_LOOP) => Err(ExtractError::NoAffineLoop),
+                (None, SLAP_EXTRACT_UNSUPPORTED_EXPR) => Err(ExtractError::UnsupportedExpr),
+                (None, _) => Err(ExtractError::ParseError),
+            }
         }
     }
 }
@@ -502,3 +1409,123 @@ pub unsafe extern "C" fn slap_allocate_index_array(ctx: *const Context, len: usi
     let ctx = &*ctx;
     ctx.arena.alloc_slice_fill_default(len).as_mut_ptr()
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        builder::{GraphBuilder, GraphRef, LoopNestBuilder},
+        graph::{AccessKind, Graph, GraphDiff},
+        Context,
+    };
+
+    /// `for i{ivar} in 0..4 { load m0[i{ivar}] }`, continuing to `exit`.
+    fn counted_loop<'a>(
+        builder: &GraphBuilder<'a>,
+        ivar: usize,
+        exit: GraphRef<'a>,
+    ) -> GraphRef<'a> {
+        let mut coefficients = vec![0; ivar + 1];
+        coefficients[ivar] = 1;
+        let cond = builder.branch(ivar, builder.expr(&[], 4), None, Some(exit));
+        let step = builder.update(ivar, builder.expr(&coefficients, 1), Some(cond));
+        let body = builder.access(
+            0,
+            builder.expr(&coefficients, 0),
+            AccessKind::Read,
+            Some(step),
+        );
+        unsafe { cond.set_then(Some(body)) }.unwrap();
+        builder.update(ivar, builder.expr(&[], 0), Some(cond))
+    }
+
+    #[test]
+    fn loop_depth_counts_nesting() {
+        let ctx = Context::with_capacity(4096);
+        let builder = GraphBuilder::new(&ctx);
+        let second = counted_loop(&builder, 1, builder.end());
+        let first = counted_loop(&builder, 0, second);
+        let siblings = builder.start(Some(first)).get().stats();
+        assert_eq!(siblings.branch_nodes, 2);
+        assert_eq!(siblings.max_loop_depth, 1);
+        assert!(siblings.has_cycles);
+
+        let nest = LoopNestBuilder::new(&ctx)
+            .for_loop(builder.expr(&[], 0), builder.expr(&[], 4), 1)
+            .for_loop(builder.expr(&[], 0), builder.expr(&[], 4), 1)
+            .load(0, builder.expr(&[8, 1], 0))
+            .build()
+            .stats();
+        assert_eq!(nest.max_loop_depth, 2);
+
+        let straight = builder.start(Some(builder.end())).get().stats();
+        assert_eq!(straight.max_loop_depth, 0);
+        assert!(!straight.has_cycles);
+    }
+
+    #[test]
+    fn query_accesses_by_loop() {
+        let ctx = Context::with_capacity(4096);
+        let builder = GraphBuilder::new(&ctx);
+        let nest = LoopNestBuilder::new(&ctx)
+            .for_loop(builder.expr(&[], 0), builder.expr(&[], 4), 1)
+            .for_loop(builder.expr(&[], 0), builder.expr(&[], 4), 1)
+            .load(0, builder.expr(&[8, 1], 0))
+            .store(1, builder.expr(&[8, 1], 0))
+            .build();
+        assert_eq!(nest.accesses_in_loop(0).len(), 2);
+        assert_eq!(nest.accesses_in_loop(1).len(), 2);
+        assert!(nest.accesses_in_loop(2).is_empty());
+        let updates = |ivar| nest.find_in_loop(ivar, |x| matches!(x, Graph::Update { .. }));
+        // Both steps and the entry of the inner loop, but not the outer entry.
+        assert_eq!(updates(0).len(), 3);
+        assert_eq!(updates(1).len(), 1);
+
+        let second = counted_loop(&builder, 1, builder.end());
+        let first = counted_loop(&builder, 0, second);
+        let siblings = builder.start(Some(first)).get();
+        let [Graph::Access { offset, .. }] = siblings.accesses_in_loop(1)[..] else {
+            panic!("expected the access of the second loop only");
+        };
+        assert_eq!(offset.coefficients(), [0, 1]);
+    }
+
+    #[test]
+    fn diff_reports_single_splice() {
+        let ctx = Context::with_capacity(4096);
+        let builder = GraphBuilder::new(&ctx);
+        let old = LoopNestBuilder::new(&ctx)
+            .for_loop(builder.expr(&[], 0), builder.expr(&[], 4), 1)
+            .load(0, builder.expr(&[8], 0))
+            .store(1, builder.expr(&[8], 0))
+            .build();
+        let new = old.deep_clone_into(&ctx);
+        let target = new
+            .iter()
+            .find(|x| matches!(x, Graph::Access { memref: 1, .. }))
+            .unwrap();
+        let inserted = builder.access(2, builder.expr(&[], 0), AccessKind::Read, None);
+        unsafe { new.splice_before(target, inserted.get()) }.unwrap();
+
+        let added = old.diff(new);
+        assert_eq!(added.len(), 1, "{added:?}");
+        assert!(matches!(added[0], GraphDiff::AddedAccess(x) if std::ptr::eq(x, inserted.get())));
+        let removed = new.diff(old);
+        assert_eq!(removed.len(), 1, "{removed:?}");
+        assert!(
+            matches!(removed[0], GraphDiff::RemovedAccess(x) if std::ptr::eq(x, inserted.get()))
+        );
+        assert!(old.diff(old.deep_clone_into(&ctx)).is_empty());
+    }
+
+    #[test]
+    fn labels_branch_with_equal_sides() {
+        let ctx = Context::with_capacity(4096);
+        let builder = GraphBuilder::new(&ctx);
+        let end = builder.end();
+        let branch = builder.branch(0, builder.expr(&[], 1), Some(end), Some(end));
+        let graph = builder.start(Some(branch)).get();
+        let text = format!("{graph:?}");
+        assert!(!text.contains("..."), "{text}");
+        assert!(text.contains("#0") && text.contains("ref #0"), "{text}");
+    }
+}
@@ -0,0 +1,53 @@
+// The `extern "C"` entry points are called by the extractor and the native
+// simulator, whose pointer contracts are documented in `include/slap.h`.
+#![allow(clippy::missing_safety_doc)]
+
+use std::cell::UnsafeCell;
+
+pub mod affine;
+pub mod builder;
+pub mod graph;
+pub mod mlir;
+pub mod optimize;
+pub mod serialize;
+pub mod simulator;
+pub mod visitor;
+
+pub struct Context {
+    arena: bumpalo::Bump,
+    pub dump_node: bool,
+    pub printer: UnsafeCell<Box<dyn std::io::Write>>,
+}
+
+impl Context {
+    /// Create a context whose arena pre-allocates `bytes` up front.
+    ///
+    /// Each graph node costs roughly `64 + 8 * n` bytes of arena space, where
+    /// `n` is the number of induction variables in the nest (the node itself
+    /// plus its affine expression). Sizing the arena to `nodes * (64 + 8 * n)`
+    /// avoids the chunk growth that otherwise dominates early allocation.
+    pub fn with_capacity(bytes: usize) -> Self {
+        Self {
+            arena: bumpalo::Bump::with_capacity(bytes),
+            dump_node: false,
+            printer: UnsafeCell::new(Box::new(std::io::stderr())),
+        }
+    }
+}
+
+#[no_mangle]
+unsafe extern "C" fn slap_dump_node_of_affine_access(ctx: *const Context) -> bool {
+    (*ctx).dump_node
+}
+
+// void slap_print_callback(const char *, size_t, void *);
+#[no_mangle]
+unsafe extern "C" fn slap_print_callback(
+    data: *const std::os::raw::c_char,
+    len: usize,
+    ctx: *mut std::ffi::c_void,
+) {
+    let ctx = &mut *(ctx as *mut Context);
+    let data = std::slice::from_raw_parts(data, len);
+    (*ctx.printer.get()).write_all(data).unwrap();
+}
@@ -1,16 +1,7 @@
 use std::{cell::UnsafeCell, path::PathBuf};
 
 use clap::Parser;
-
-mod affine;
-mod graph;
-mod simulator;
-
-pub struct Context {
-    arena: bumpalo::Bump,
-    dump_node: bool,
-    printer: UnsafeCell<Box<dyn std::io::Write>>,
-}
+use slap::{graph, simulator, Context};
 
 #[derive(clap::Parser)]
 enum Command {
@@ -22,6 +13,15 @@ enum Command {
         #[clap(short, long)]
         /// Path to the output file, if not provided, the result will be printed to stdout
         output: Option<PathBuf>,
+        #[clap(long, default_value_t = 0)]
+        /// Number of bytes to pre-allocate for the graph arena
+        arena_capacity: usize,
+        #[clap(long, value_parser = parse_memref_block_size)]
+        /// Per-memref block size override, given as `MEMREF=BYTES` (repeatable)
+        memref_block_size: Vec<(usize, usize)>,
+        #[clap(long)]
+        /// Record LRU stack distances instead of logic-time reuse intervals
+        stack_distance: bool,
     },
     /// Vectorize the given affine program into training data
     Vectorize {
@@ -34,24 +34,52 @@ enum Command {
         /// Path to the node data output file, if not provided, the result will be printed to stdout
         #[clap(short, long)]
         data: Option<PathBuf>,
+        /// Number of bytes to pre-allocate for the graph arena
+        #[clap(long, default_value_t = 0)]
+        arena_capacity: usize,
+        /// Per-memref block size override, given as `MEMREF=BYTES` (repeatable)
+        #[clap(long, value_parser = parse_memref_block_size)]
+        memref_block_size: Vec<(usize, usize)>,
+        /// Record LRU stack distances instead of logic-time reuse intervals
+        #[clap(long)]
+        stack_distance: bool,
+    },
+    /// Count the distinct blocks (compulsory misses) touched by each memref
+    Footprint {
+        #[clap(short, long)]
+        /// Path to the affine program
+        input: PathBuf,
+        #[clap(short, long)]
+        /// Path to the output file, if not provided, the result will be printed to stdout
+        output: Option<PathBuf>,
     },
 }
 
-#[no_mangle]
-unsafe extern "C" fn slap_dump_node_of_affine_access(ctx: *const Context) -> bool {
-    (*ctx).dump_node
+fn distance_mode(stack_distance: bool) -> simulator::DistanceMode {
+    if stack_distance {
+        simulator::DistanceMode::StackDistance
+    } else {
+        simulator::DistanceMode::LogicalTime
+    }
 }
 
-// void slap_print_callback(const char *, size_t, void *);
-#[no_mangle]
-unsafe extern "C" fn slap_print_callback(
-    data: *const std::os::raw::c_char,
-    len: usize,
-    ctx: *mut std::ffi::c_void,
-) {
-    let ctx = &mut *(ctx as *mut Context);
-    let data = std::slice::from_raw_parts(data, len);
-    (*ctx.printer.get()).write_all(data).unwrap();
+/// Unwrap `result`, or report its error after `context` and exit non-zero.
+fn or_exit<T, E: std::fmt::Display>(result: Result<T, E>, context: &dyn std::fmt::Display) -> T {
+    result.unwrap_or_else(|e| {
+        eprintln!("{context}: {e}");
+        std::process::exit(1)
+    })
+}
+
+fn parse_memref_block_size(s: &str) -> Result<(usize, usize), String> {
+    let (memref, size) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected MEMREF=BYTES, got `{s}`"))?;
+    let memref = memref.parse().map_err(|e| format!("invalid memref: {e}"))?;
+    let size = size
+        .parse()
+        .map_err(|e| format!("invalid block size: {e}"))?;
+    Ok((memref, size))
 }
 
 fn main() {
@@ -60,40 +88,57 @@ fn main() {
         simulator::slap_initialize_llvm();
     }
     match cmd {
-        Command::Distribution { input, output } => {
-            let ctx = Context {
-                arena: bumpalo::Bump::new(),
-                dump_node: true,
-                printer: UnsafeCell::new(
-                    output
-                        .map(|x| {
-                            Box::new(std::fs::File::create(x).unwrap()) as Box<dyn std::io::Write>
-                        })
-                        .unwrap_or_else(|| Box::new(std::io::stdout())),
-                ),
-            };
-            let (g, vaddrs) = graph::Graph::new_from_file(&ctx, &format!("{}", input.display()))
-                .expect("failed to parse mlir");
+        Command::Distribution {
+            input,
+            output,
+            arena_capacity,
+            memref_block_size,
+            stack_distance,
+        } => {
+            let mut ctx = Context::with_capacity(arena_capacity);
+            ctx.dump_node = true;
+            ctx.printer = UnsafeCell::new(
+                output
+                    .map(|x| {
+                        Box::new(or_exit(std::fs::File::create(&x), &x.display()))
+                            as Box<dyn std::io::Write>
+                    })
+                    .unwrap_or_else(|| Box::new(std::io::stdout())),
+            );
+            let (g, vaddrs) = or_exit(
+                graph::Graph::new_from_file(&ctx, &format!("{}", input.display())),
+                &input.display(),
+            );
             unsafe {
-                let mut sctx = simulator::SimulationCtx::new(&ctx, 64, vaddrs);
+                let mut sctx =
+                    simulator::SimulationCtx::new(&ctx, 64, vaddrs, distance_mode(stack_distance))
+                        .unwrap();
+                for (memref, size) in memref_block_size.iter().copied() {
+                    or_exit(
+                        sctx.set_memref_block_size(memref, size),
+                        &format_args!("memref {memref}"),
+                    );
+                }
                 sctx.populate_node_info(g);
                 let cell = std::cell::UnsafeCell::new(sctx);
-                simulator::slap_run_simulation(&cell, g);
+                or_exit(simulator::run_simulation(&cell, g), &input.display());
                 writeln!(&mut *ctx.printer.get(), "{{").unwrap();
-                for (i, (k, v)) in (*cell.get()).address_map.iter().enumerate() {
+                let sctx = &*cell.get();
+                let nodes = sctx.node_dists().count();
+                for (i, (node, dist)) in sctx.node_dists().enumerate() {
                     write!(
                         &mut *ctx.printer.get(),
                         "\t\"{}\" : {{",
-                        k.as_ptr() as usize
+                        node as *const _ as usize
                     )
                     .unwrap();
-                    for (i, x) in (*cell.get()).node_info[*v].iter().enumerate() {
+                    for (i, x) in dist.iter().enumerate() {
                         write!(&mut *ctx.printer.get(), "\"{}\" : {}", x.0, x.1).unwrap();
-                        if i != (*cell.get()).node_info[*v].len() - 1 {
+                        if i != dist.len() - 1 {
                             write!(&mut *ctx.printer.get(), ", ").unwrap();
                         }
                     }
-                    if i != (*cell.get()).address_map.len() - 1 {
+                    if i != nodes - 1 {
                         writeln!(&mut *ctx.printer.get(), "}},").unwrap();
                     } else {
                         writeln!(&mut *ctx.printer.get(), "}}").unwrap();
@@ -106,32 +151,73 @@ fn main() {
             input,
             adjacency,
             data,
+            arena_capacity,
+            memref_block_size,
+            stack_distance,
         } => {
-            let ctx = Context {
-                arena: bumpalo::Bump::new(),
-                dump_node: false,
-                printer: UnsafeCell::new(Box::new(std::io::stderr())),
-            };
-            let (g, vaddrs) = graph::Graph::new_from_file(&ctx, &format!("{}", input.display()))
-                .expect("failed to parse mlir");
+            let ctx = Context::with_capacity(arena_capacity);
+            let (g, vaddrs) = or_exit(
+                graph::Graph::new_from_file(&ctx, &format!("{}", input.display())),
+                &input.display(),
+            );
             let adj = g.adjacency();
             let mut adj_writer = adjacency
-                .map(|x| Box::new(std::fs::File::create(x).unwrap()) as Box<dyn std::io::Write>)
+                .map(|x| {
+                    Box::new(or_exit(std::fs::File::create(&x), &x.display()))
+                        as Box<dyn std::io::Write>
+                })
                 .unwrap_or_else(|| Box::new(std::io::stdout()));
             let adj_json = serde_json::to_string_pretty(&adj).unwrap();
             adj_writer.write_all(adj_json.as_bytes()).unwrap();
             let mut data_writer = data
-                .map(|x| Box::new(std::fs::File::create(x).unwrap()) as Box<dyn std::io::Write>)
+                .map(|x| {
+                    Box::new(or_exit(std::fs::File::create(&x), &x.display()))
+                        as Box<dyn std::io::Write>
+                })
                 .unwrap_or_else(|| Box::new(std::io::stdout()));
             unsafe {
-                let mut sctx = simulator::SimulationCtx::new(&ctx, 64, vaddrs);
+                let mut sctx =
+                    simulator::SimulationCtx::new(&ctx, 64, vaddrs, distance_mode(stack_distance))
+                        .unwrap();
+                for (memref, size) in memref_block_size.iter().copied() {
+                    or_exit(
+                        sctx.set_memref_block_size(memref, size),
+                        &format_args!("memref {memref}"),
+                    );
+                }
                 sctx.populate_node_info(g);
                 let cell = std::cell::UnsafeCell::new(sctx);
-                simulator::slap_run_simulation(&cell, g);
+                or_exit(simulator::run_simulation(&cell, g), &input.display());
                 let vectorized = g.vectorize_all(&*cell.get());
                 let json = serde_json::to_string_pretty(&vectorized).unwrap();
                 data_writer.write_all(json.as_bytes()).unwrap();
             }
         }
+        Command::Footprint { input, output } => {
+            let ctx = Context::with_capacity(0);
+            let (g, vaddrs) = or_exit(
+                graph::Graph::new_from_file(&ctx, &format!("{}", input.display())),
+                &input.display(),
+            );
+            let mut writer = output
+                .map(|x| {
+                    Box::new(or_exit(std::fs::File::create(&x), &x.display()))
+                        as Box<dyn std::io::Write>
+                })
+                .unwrap_or_else(|| Box::new(std::io::stdout()));
+            unsafe {
+                let mut sctx = simulator::SimulationCtx::new(&ctx, 64, vaddrs, Default::default())
+                    .unwrap()
+                    .with_cold_only();
+                sctx.populate_node_info(g);
+                let cell = std::cell::UnsafeCell::new(sctx);
+                or_exit(simulator::run_simulation(&cell, g), &input.display());
+                let footprint = (0..vaddrs.len())
+                    .map(|memref| (memref, (*cell.get()).memref_footprint(memref)))
+                    .collect::<std::collections::BTreeMap<_, _>>();
+                let json = serde_json::to_string_pretty(&footprint).unwrap();
+                writer.write_all(json.as_bytes()).unwrap();
+            }
+        }
     }
 }
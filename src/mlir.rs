@@ -0,0 +1,328 @@
+use std::{fmt::Write, ptr::NonNull};
+
+use rustc_hash::{FxHashMap, FxHashSet};
+
+use crate::{
+    affine::Expr,
+    graph::{AccessKind, BranchKind, BranchSide, Graph},
+};
+
+/// Why [`Graph::to_affine_mlir`] could not express a graph in the affine
+/// dialect.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MlirError {
+    /// The root is not a `Start` node.
+    MissingStart,
+    /// A `Branch` that is not the header of a loop, or whose body is on the
+    /// `else` side.
+    UnstructuredBranch,
+    /// An `Update` that does not start a loop on its induction variable.
+    StrayUpdate,
+    /// A loop step that is not a positive constant increment.
+    NonConstantStep,
+    /// An expression uses an induction variable no enclosing loop defines.
+    UnboundIvar(usize),
+    /// Control flow that does not nest into loops, e.g. a loop body that is
+    /// left other than through its step.
+    Irreducible,
+}
+
+impl std::fmt::Display for MlirError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MlirError::MissingStart => write!(f, "graph does not begin with a start node"),
+            MlirError::UnstructuredBranch => write!(f, "branch is not a loop header"),
+            MlirError::StrayUpdate => write!(f, "update does not start a loop"),
+            MlirError::NonConstantStep => write!(f, "loop step is not a positive constant"),
+            MlirError::UnboundIvar(ivar) => write!(f, "i{ivar} is used outside of its loop"),
+            MlirError::Irreducible => write!(f, "control flow does not form nested loops"),
+        }
+    }
+}
+
+impl std::error::Error for MlirError {}
+
+/// `expr` in affine expression syntax, naming induction variable `k` by
+/// `name(k)`, e.g. `d0 * 2 - (d1 floordiv 4) + 3`.
+fn affine_text(expr: &Expr, name: &dyn Fn(usize) -> String) -> String {
+    let ivars = expr
+        .coefficients()
+        .iter()
+        .enumerate()
+        .map(|(k, c)| (*c, name(k)));
+    let terms = expr.terms().iter().map(|x| {
+        let operand = affine_text(x.operand, name);
+        (
+            x.coefficient,
+            format!("({operand}) {} {}", x.op.name(), x.divisor),
+        )
+    });
+    let mut text = String::new();
+    for (c, factor) in ivars.chain(terms) {
+        let sep = match (text.is_empty(), c) {
+            (_, 0) => continue,
+            (true, c) if c < 0 => "-",
+            (true, _) => "",
+            (false, c) if c < 0 => " - ",
+            (false, _) => " + ",
+        };
+        text.push_str(sep);
+        text.push_str(&factor);
+        if c.unsigned_abs() != 1 {
+            let _ = write!(text, " * {}", c.unsigned_abs());
+        }
+    }
+    match expr.bias() {
+        b if text.is_empty() => text = b.to_string(),
+        0 => (),
+        b if b < 0 => {
+            let _ = write!(text, " - {}", b.unsigned_abs());
+        }
+        b => {
+            let _ = write!(text, " + {b}");
+        }
+    }
+    text
+}
+
+struct Emitter<'a> {
+    out: String,
+    predecessors: FxHashMap<NonNull<Graph<'a>>, Vec<NonNull<Graph<'a>>>>,
+    kinds: FxHashMap<NonNull<Graph<'a>>, BranchKind>,
+    emitted: FxHashSet<NonNull<Graph<'a>>>,
+    /// Induction variables of the enclosing loops, outermost first.
+    scope: Vec<usize>,
+    values: usize,
+}
+
+impl<'a> Emitter<'a> {
+    fn line(&mut self, text: std::fmt::Arguments) {
+        let indent = 2 * (self.scope.len() + 1);
+        let _ = writeln!(self.out, "{:indent$}{text}", "");
+    }
+
+    fn ivars(&self, expr: &Expr) -> Result<Vec<usize>, MlirError> {
+        fn collect(expr: &Expr, ivars: &mut Vec<usize>) {
+            for (k, c) in expr.coefficients().iter().enumerate() {
+                if *c != 0 && !ivars.contains(&k) {
+                    ivars.push(k);
+                }
+            }
+            for term in expr.terms() {
+                collect(term.operand, ivars);
+            }
+        }
+        let mut ivars = Vec::new();
+        collect(expr, &mut ivars);
+        ivars.sort_unstable();
+        match ivars.iter().find(|k| !self.scope.contains(k)) {
+            Some(k) => Err(MlirError::UnboundIvar(*k)),
+            None => Ok(ivars),
+        }
+    }
+
+    /// A loop bound: a constant, or an inline `affine_map` applied to the
+    /// induction variables it uses.
+    fn bound(&self, expr: &Expr) -> Result<String, MlirError> {
+        let ivars = self.ivars(expr)?;
+        if let Some(x) = expr.constant() {
+            return Ok(x.to_string());
+        }
+        let dims = (0..ivars.len())
+            .map(|d| format!("d{d}"))
+            .collect::<Vec<_>>();
+        let map = affine_text(expr, &|k| {
+            let d = ivars.iter().position(|x| *x == k).unwrap();
+            format!("d{d}")
+        });
+        let operands = ivars.iter().map(|k| format!("%i{k}")).collect::<Vec<_>>();
+        Ok(format!(
+            "affine_map<({}) -> ({map})>({})",
+            dims.join(", "),
+            operands.join(", ")
+        ))
+    }
+
+    fn access(&mut self, memref: usize, offset: &Expr, kind: AccessKind) -> Result<(), MlirError> {
+        self.ivars(offset)?;
+        let index = affine_text(offset, &|k| format!("%i{k}"));
+        if matches!(kind, AccessKind::Read | AccessKind::ReadWrite) {
+            let value = self.values;
+            self.values += 1;
+            self.line(format_args!(
+                "%v{value} = affine.load %m{memref}[{index}] : memref<?xi8>"
+            ));
+        }
+        if matches!(kind, AccessKind::Write | AccessKind::ReadWrite) {
+            self.line(format_args!(
+                "affine.store %c0, %m{memref}[{index}] : memref<?xi8>"
+            ));
+        }
+        Ok(())
+    }
+
+    /// Emit the nodes from `node` on until `stop`, the step `Update` of the
+    /// enclosing loop, or until the graph ends if there is none.
+    fn sequence(
+        &mut self,
+        mut node: Option<&'a Graph<'a>>,
+        stop: Option<&'a Graph<'a>>,
+    ) -> Result<(), MlirError> {
+        loop {
+            let Some(current) = node else {
+                return stop.map_or(Ok(()), |_| Err(MlirError::Irreducible));
+            };
+            if stop.is_some_and(|x| std::ptr::eq(x, current)) {
+                return Ok(());
+            }
+            if !self.emitted.insert(NonNull::from(current)) {
+                return Err(MlirError::Irreducible);
+            }
+            node = match current {
+                Graph::Start(_) => return Err(MlirError::Irreducible),
+                Graph::End => return stop.map_or(Ok(()), |_| Err(MlirError::Irreducible)),
+                Graph::Access {
+                    memref,
+                    offset,
+                    kind,
+                    next,
+                    ..
+                } => {
+                    self.access(*memref, offset, *kind)?;
+                    *next
+                }
+                Graph::Update { ivar, expr, next } => self.for_loop(current, *ivar, expr, *next)?,
+                Graph::Branch { .. } => return Err(MlirError::UnstructuredBranch),
+            };
+        }
+    }
+
+    /// Emit the loop started by `init`, the `Update` of `ivar` to `lower`
+    /// that leads to the loop header, returning where control goes once the
+    /// loop exits.
+    fn for_loop(
+        &mut self,
+        init: &'a Graph<'a>,
+        ivar: usize,
+        lower: &Expr,
+        header: Option<&'a Graph<'a>>,
+    ) -> Result<Option<&'a Graph<'a>>, MlirError> {
+        let Some(
+            header @ Graph::Branch {
+                ivar: cond,
+                bound,
+                then,
+                r#else,
+            },
+        ) = header
+        else {
+            return Err(MlirError::StrayUpdate);
+        };
+        if *cond != ivar {
+            return Err(MlirError::StrayUpdate);
+        }
+        let Some(BranchKind::Loop {
+            body_side: BranchSide::Then,
+            ..
+        }) = self.kinds.get(&NonNull::from(header))
+        else {
+            return Err(MlirError::UnstructuredBranch);
+        };
+        let steps = self.predecessors[&NonNull::from(header)]
+            .iter()
+            .map(|x| unsafe { x.as_ref() })
+            .filter(|x| !std::ptr::eq(*x, init))
+            .collect::<Vec<_>>();
+        let [step_node @ Graph::Update {
+            ivar: stepped,
+            expr: step,
+            ..
+        }] = steps[..]
+        else {
+            return Err(MlirError::Irreducible);
+        };
+        if *stepped != ivar {
+            return Err(MlirError::Irreducible);
+        }
+        let coefficients = step.coefficients();
+        let is_increment = step.is_affine()
+            && coefficients.get(ivar) == Some(&1)
+            && coefficients
+                .iter()
+                .enumerate()
+                .all(|(k, c)| k == ivar || *c == 0);
+        if !is_increment || step.bias() <= 0 {
+            return Err(MlirError::NonConstantStep);
+        }
+        if self.scope.contains(&ivar) || !self.emitted.insert(NonNull::from(header)) {
+            return Err(MlirError::Irreducible);
+        }
+        let lower = self.bound(lower)?;
+        let upper = self.bound(bound)?;
+        let step = match step.bias() {
+            1 => String::new(),
+            s => format!(" step {s}"),
+        };
+        self.line(format_args!(
+            "affine.for %i{ivar} = {lower} to {upper}{step} {{"
+        ));
+        self.scope.push(ivar);
+        self.sequence(*then, Some(step_node))?;
+        self.scope.pop();
+        self.line(format_args!("}}"));
+        Ok(*r#else)
+    }
+}
+
+impl<'a> Graph<'a> {
+    /// Print the graph as an MLIR function in the affine dialect, undoing
+    /// the lowering of the extractor: loops become `affine.for` over `%i<k>`
+    /// for induction variable `k`, and accesses become `affine.load` or
+    /// `affine.store` on the function argument `%m<memref>`.
+    ///
+    /// Offsets are in bytes, so every memref is typed `memref<?xi8>` and
+    /// indexed by its offset expression. Fails with an [`MlirError`] unless
+    /// the graph nests into loops with constant positive steps, the way the
+    /// extractor emits them.
+    pub fn to_affine_mlir(&'a self) -> Result<String, MlirError> {
+        let Graph::Start(next) = self else {
+            return Err(MlirError::MissingStart);
+        };
+        let memrefs = self
+            .iter()
+            .filter_map(|x| match x {
+                Graph::Access { memref, .. } => Some(*memref),
+                _ => None,
+            })
+            .max()
+            .map_or(0, |x| x + 1);
+        let has_stores = self.iter().any(|x| {
+            matches!(
+                x,
+                Graph::Access {
+                    kind: AccessKind::Write | AccessKind::ReadWrite,
+                    ..
+                }
+            )
+        });
+        let mut emitter = Emitter {
+            out: String::new(),
+            predecessors: self.build_predecessor_map(),
+            kinds: self.classify_branches(),
+            emitted: FxHashSet::default(),
+            scope: Vec::new(),
+            values: 0,
+        };
+        let args = (0..memrefs)
+            .map(|x| format!("%m{x}: memref<?xi8>"))
+            .collect::<Vec<_>>();
+        let _ = writeln!(emitter.out, "func.func @slap({}) {{", args.join(", "));
+        if has_stores {
+            emitter.line(format_args!("%c0 = arith.constant 0 : i8"));
+        }
+        emitter.sequence(*next, None)?;
+        emitter.line(format_args!("return"));
+        emitter.out.push_str("}\n");
+        Ok(emitter.out)
+    }
+}
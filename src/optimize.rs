@@ -0,0 +1,337 @@
+use std::{ops::RangeInclusive, ptr::NonNull};
+
+use rustc_hash::{FxHashMap, FxHashSet};
+
+use crate::{graph::Graph, Context};
+
+impl<'a> Graph<'a> {
+    /// Side a `Branch` always takes, if that is statically known: both sides
+    /// are the same node, or every predecessor assigns the branch's ivar a
+    /// constant and all of them compare the same way against a constant
+    /// bound.
+    fn folded_side(
+        &'a self,
+        predecessors: &FxHashMap<NonNull<Graph<'a>>, Vec<NonNull<Graph<'a>>>>,
+    ) -> Option<&'a Graph<'a>> {
+        let Graph::Branch {
+            ivar,
+            bound,
+            then,
+            r#else,
+        } = self
+        else {
+            return None;
+        };
+        if let (Some(x), Some(y)) = (then, r#else) {
+            if std::ptr::eq(*x, *y) {
+                return Some(x);
+            }
+        }
+        let bound = bound.constant()?;
+        let mut taken = None;
+        for pred in predecessors.get(&NonNull::from(self))? {
+            let pred = unsafe { pred.as_ref() };
+            let Graph::Update {
+                ivar: target, expr, ..
+            } = pred
+            else {
+                return None;
+            };
+            if target != ivar {
+                return None;
+            }
+            let side = expr.constant()? < bound;
+            if taken.replace(side).is_some_and(|x| x != side) {
+                return None;
+            }
+        }
+        if taken? {
+            *then
+        } else {
+            *r#else
+        }
+    }
+
+    /// Copy the graph into `ctx` with statically decided branches replaced by
+    /// the side they always take. Nodes that become unreachable, and any that
+    /// already were, are not copied.
+    pub fn optimize<'b>(&'a self, ctx: &'b Context) -> &'b Graph<'b> {
+        let predecessors = self.build_predecessor_map();
+        let folded = self
+            .iter()
+            .filter_map(|x| Some((NonNull::from(x), x.folded_side(&predecessors)?)))
+            .collect();
+        self.fold_branches(ctx, &folded)
+    }
+
+    /// Copy the graph into `ctx`, replacing every `Branch` that can only take
+    /// one side by that side. Induction variables start at `constants`, or
+    /// at zero past its end, as in [`SimulationCtx::access_trace`]; an
+    /// interval analysis then bounds every ivar at every node, and a branch
+    /// is dead on one side if no value in the ranges of its ivar and bound
+    /// can take it. The result is only equivalent for the same `constants`.
+    ///
+    /// [`SimulationCtx::access_trace`]: crate::simulator::SimulationCtx::access_trace
+    pub fn eliminate_dead_branches<'b>(
+        &'a self,
+        constants: &[isize],
+        ctx: &'b Context,
+    ) -> &'b Graph<'b> {
+        let ranges = self.ivar_ranges(constants);
+        let folded = self
+            .iter()
+            .filter_map(|node| {
+                let Graph::Branch { then, r#else, .. } = node else {
+                    return None;
+                };
+                let (taken, not_taken) = node.branch_ranges(ranges.get(&NonNull::from(node))?);
+                let side = match (taken, not_taken) {
+                    (Some(_), None) => *then,
+                    (None, Some(_)) => *r#else,
+                    _ => None,
+                };
+                Some((NonNull::from(node), side?))
+            })
+            .collect();
+        self.fold_branches(ctx, &folded)
+    }
+
+    fn fold_branches<'b>(
+        &'a self,
+        ctx: &'b Context,
+        folded: &FxHashMap<NonNull<Graph<'a>>, &'a Graph<'a>>,
+    ) -> &'b Graph<'b> {
+        self.clone_redirected(ctx, |mut node| {
+            // A cycle made only of folded branches never reaches an access,
+            // so keep its first branch rather than chasing it forever.
+            let mut seen = FxHashSet::default();
+            while let Some(next) = folded.get(&NonNull::from(node)) {
+                if !seen.insert(NonNull::from(node)) {
+                    break;
+                }
+                node = next;
+            }
+            node
+        })
+    }
+
+    /// Ranges of every ivar on entry to each reachable node. Ranges grow by
+    /// joining the states of all edges into a node; once the target of a
+    /// back-edge has been joined `WIDEN_AFTER` times, bounds that still move
+    /// are dropped to `isize::MIN` or `isize::MAX`, so loops reach a fixed
+    /// point. The branch on a loop's ivar restores its bound in the body.
+    fn ivar_ranges(&'a self, constants: &[isize]) -> FxHashMap<NonNull<Graph<'a>>, Ranges> {
+        const WIDEN_AFTER: usize = 2;
+        let count = self
+            .iter()
+            .map(|x| match x {
+                Graph::Access { offset, .. } => offset.ivar_count(),
+                Graph::Update { ivar, expr, .. } => expr.ivar_count().max(ivar + 1),
+                Graph::Branch { ivar, bound, .. } => bound.ivar_count().max(ivar + 1),
+                Graph::Start(_) | Graph::End => 0,
+            })
+            .fold(constants.len(), usize::max);
+        let entry = (0..count)
+            .map(|x| constants.get(x).map_or(0..=0, |x| *x..=*x))
+            .collect::<Ranges>();
+        let widened = self.back_edge_targets();
+        let mut states = FxHashMap::<_, Ranges>::default();
+        let mut joins = FxHashMap::<_, usize>::default();
+        let mut work = vec![(self, entry)];
+        while let Some((node, state)) = work.pop() {
+            let ptr = NonNull::from(node);
+            let state = match states.get(&ptr) {
+                None => state,
+                Some(old) => {
+                    let joins = joins.entry(ptr).or_insert(0);
+                    *joins += 1;
+                    let widen = *joins > WIDEN_AFTER && widened.contains(&ptr);
+                    let joined = old
+                        .iter()
+                        .zip(&state)
+                        .map(|(old, new)| {
+                            let lo = *old.start().min(new.start());
+                            let hi = *old.end().max(new.end());
+                            let lo = if widen && lo < *old.start() {
+                                isize::MIN
+                            } else {
+                                lo
+                            };
+                            let hi = if widen && hi > *old.end() {
+                                isize::MAX
+                            } else {
+                                hi
+                            };
+                            lo..=hi
+                        })
+                        .collect::<Ranges>();
+                    if joined == *old {
+                        continue;
+                    }
+                    joined
+                }
+            };
+            match node {
+                Graph::Start(next) | Graph::Access { next, .. } => {
+                    work.extend(next.map(|x| (x, state.clone())));
+                }
+                Graph::Update { ivar, expr, next } => {
+                    let mut after = state.clone();
+                    after[*ivar] = expr.range(&state);
+                    work.extend(next.map(|x| (x, after)));
+                }
+                Graph::Branch { then, r#else, .. } => {
+                    let (taken, not_taken) = node.branch_ranges(&state);
+                    work.extend(then.zip(taken));
+                    work.extend(r#else.zip(not_taken));
+                }
+                Graph::End => (),
+            }
+            states.insert(ptr, state);
+        }
+        states
+    }
+
+    /// Nodes a depth-first walk reaches again while they are still on its
+    /// path; every cycle contains one of them.
+    fn back_edge_targets(&'a self) -> FxHashSet<NonNull<Graph<'a>>> {
+        let ptr = NonNull::from(self);
+        let mut targets = FxHashSet::default();
+        let mut done = FxHashSet::from_iter([ptr]);
+        let mut stack = vec![(self, self.successors().collect::<Vec<_>>())];
+        let mut on_path = FxHashSet::from_iter([ptr]);
+        while let Some((node, successors)) = stack.last_mut() {
+            let Some(next) = successors.pop() else {
+                on_path.remove(&NonNull::from(*node));
+                stack.pop();
+                continue;
+            };
+            let next_ptr = NonNull::from(next);
+            if on_path.contains(&next_ptr) {
+                targets.insert(next_ptr);
+            } else if done.insert(next_ptr) {
+                on_path.insert(next_ptr);
+                stack.push((next, next.successors().collect()));
+            }
+        }
+        targets
+    }
+
+    /// States on the `then` and `else` side of a `Branch` reached in
+    /// `state`, `None` for a side no value in `state` can take.
+    fn branch_ranges(&self, state: &Ranges) -> (Option<Ranges>, Option<Ranges>) {
+        let Graph::Branch { ivar, bound, .. } = self else {
+            return (None, None);
+        };
+        let bound = bound.range(state);
+        let (lo, hi) = (*state[*ivar].start(), *state[*ivar].end());
+        let side = |lo: isize, hi: isize| {
+            let mut state = state.clone();
+            state[*ivar] = lo..=hi;
+            (lo <= hi).then_some(state)
+        };
+        // `ivar < bound` needs `ivar < bound.end()`; `ivar >= bound` needs
+        // `ivar >= bound.start()`.
+        let taken = bound.end().checked_sub(1).and_then(|x| side(lo, hi.min(x)));
+        let not_taken = side(lo.max(*bound.start()), hi);
+        (taken, not_taken)
+    }
+}
+
+/// Range of each ivar, indexed by ivar.
+type Ranges = Vec<RangeInclusive<isize>>;
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use crate::{
+        builder::GraphBuilder,
+        graph::{AccessKind, Graph},
+        simulator::{DistanceMode, SimulationCtx},
+        Context,
+    };
+
+    /// `for i0 in 0..8 { if i0 < 100 { load m0 } else { load m1 }; if i1 < 3
+    /// { store m2 } else { store m3 }; if i0 < 4 { load m4 } }`, with `i1`
+    /// left to the constants.
+    fn guarded_loop(ctx: &Context) -> &Graph<'_> {
+        let builder = GraphBuilder::new(ctx);
+        let offset = builder.expr(&[8], 0);
+        let cond = builder.branch(0, builder.expr(&[], 8), None, Some(builder.end()));
+        let step = builder.update(0, builder.expr(&[1], 1), Some(cond));
+        let low = builder.access(4, offset, AccessKind::Read, Some(step));
+        let third = builder.branch(0, builder.expr(&[], 4), Some(low), Some(step));
+        let store = |memref| builder.access(memref, offset, AccessKind::Write, Some(third));
+        let second = builder.branch(1, builder.expr(&[], 3), Some(store(2)), Some(store(3)));
+        let load = |memref| builder.access(memref, offset, AccessKind::Read, Some(second));
+        let first = builder.branch(0, builder.expr(&[], 100), Some(load(0)), Some(load(1)));
+        unsafe { cond.set_then(Some(first)) }.unwrap();
+        let init = builder.update(0, builder.expr(&[], 0), Some(cond));
+        builder.start(Some(init)).get()
+    }
+
+    #[test]
+    fn dead_branches_keep_the_trace() {
+        let ctx = Context::with_capacity(1 << 14);
+        let graph = guarded_loop(&ctx);
+        let vaddrs = [0, 4096, 8192, 12288, 16384];
+        let sctx = SimulationCtx::new(&ctx, 8, &vaddrs, DistanceMode::LogicalTime).unwrap();
+        for (i1, kept) in [(5, 3), (1, 2)] {
+            let constants = [0, i1];
+            let folded = graph.eliminate_dead_branches(&constants, &ctx);
+            assert!(sctx.traces_equivalent(graph, folded, &constants).unwrap());
+            // The loop header and `i0 < 4` depend on the iteration.
+            assert_eq!(folded.stats().branch_nodes, 2);
+            let memrefs = folded.reachable_memrefs_from(folded);
+            assert_eq!(memrefs.into_iter().collect::<Vec<_>>(), [0, kept, 4]);
+        }
+    }
+
+    /// `for i0 in 0..64 { load m0[8*i0]; i1 = 0; if i1 < 1 { load m1[8*i0] }
+    /// else { load m2[0] }; if i0 < 9 {} else {}; store m0[8*i0] }`.
+    fn constant_guard(ctx: &Context) -> &Graph<'_> {
+        let builder = GraphBuilder::new(ctx);
+        let offset = builder.expr(&[8], 0);
+        let cond = builder.branch(0, builder.expr(&[], 64), None, Some(builder.end()));
+        let step = builder.update(0, builder.expr(&[1], 1), Some(cond));
+        let store = builder.access(0, offset, AccessKind::Write, Some(step));
+        let same = builder.branch(0, builder.expr(&[], 9), Some(store), Some(store));
+        let taken = builder.access(1, offset, AccessKind::Read, Some(same));
+        let dead = builder.access(2, builder.expr(&[], 0), AccessKind::Read, Some(same));
+        let guard = builder.branch(1, builder.expr(&[], 1), Some(taken), Some(dead));
+        let reset = builder.update(1, builder.expr(&[], 0), Some(guard));
+        let load = builder.access(0, offset, AccessKind::Read, Some(reset));
+        unsafe { cond.set_then(Some(load)) }.unwrap();
+        let init = builder.update(0, builder.expr(&[], 0), Some(cond));
+        builder.start(Some(init)).get()
+    }
+
+    /// Histograms of the accesses that ran, in the order of `Graph::iter`.
+    fn histograms<'a>(ctx: &'a Context, graph: &'a Graph<'a>) -> Vec<BTreeMap<usize, usize>> {
+        let vaddrs = [0, 4096, 8192];
+        let mut sctx = SimulationCtx::new(ctx, 64, &vaddrs, DistanceMode::LogicalTime).unwrap();
+        sctx.populate_node_info(graph);
+        sctx.run(graph).unwrap();
+        graph
+            .iter()
+            .filter(|x| matches!(x, Graph::Access { .. }))
+            .filter_map(|x| Some(sctx.get_node_dist(x)?.clone()))
+            .filter(|x| !x.is_empty())
+            .collect()
+    }
+
+    #[test]
+    fn optimize_keeps_the_histograms() {
+        let ctx = Context::with_capacity(1 << 14);
+        let graph = constant_guard(&ctx);
+        let optimized = graph.optimize(&ctx);
+        assert_eq!(graph.stats().branch_nodes, 3);
+        assert_eq!(optimized.stats().branch_nodes, 1);
+        let memrefs = optimized.reachable_memrefs_from(optimized);
+        assert_eq!(memrefs.into_iter().collect::<Vec<_>>(), [0, 1]);
+        let before = histograms(&ctx, graph);
+        assert_eq!(before.len(), 3);
+        assert_eq!(before, histograms(&ctx, optimized));
+    }
+}
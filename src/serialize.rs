@@ -0,0 +1,466 @@
+use std::{collections::BTreeMap, ptr::NonNull};
+
+use rustc_hash::FxHashMap;
+
+use crate::{
+    affine::{parse_affine, write_affine, Expr, QuasiOp, QuasiTerm, TermText},
+    builder::{GraphBuilder, GraphRef},
+    graph::{AccessKind, Graph},
+    simulator::SimulationCtx,
+    Context,
+};
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+/// Affine expression detached from the arena. With the `serde` feature it
+/// is stored in the readable notation of [`Expr`]'s `Display`, e.g.
+/// `2*i0 - floordiv(i1, 4) + 3`; trailing zero coefficients are not kept.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SerializedExpr {
+    pub coefficients: Vec<isize>,
+    pub bias: isize,
+    pub terms: Vec<SerializedTerm>,
+}
+
+/// A floordiv/mod term of a [`SerializedExpr`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SerializedTerm {
+    pub coefficient: isize,
+    pub op: QuasiOp,
+    pub operand: SerializedExpr,
+    pub divisor: isize,
+}
+
+impl SerializedExpr {
+    fn new(x: &Expr) -> Self {
+        Self {
+            coefficients: x.coefficients().to_vec(),
+            bias: x.bias(),
+            terms: x
+                .terms()
+                .iter()
+                .map(|x| SerializedTerm {
+                    coefficient: x.coefficient,
+                    op: x.op,
+                    operand: Self::new(x.operand),
+                    divisor: x.divisor,
+                })
+                .collect(),
+        }
+    }
+
+    /// `None` if a divisor is not positive.
+    fn build<'a>(&self, ctx: &'a Context) -> Option<&'a Expr<'a>> {
+        let terms = self
+            .terms
+            .iter()
+            .map(|x| {
+                Some(QuasiTerm {
+                    coefficient: x.coefficient,
+                    op: x.op,
+                    operand: x.operand.build(ctx)?,
+                    divisor: x.divisor,
+                })
+            })
+            .collect::<Option<Vec<_>>>()?;
+        let expr = Expr::with_terms(ctx, &self.coefficients, self.bias, &terms)?;
+        Some(ctx.arena.alloc(expr))
+    }
+}
+
+impl std::fmt::Display for SerializedExpr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let terms = self
+            .terms
+            .iter()
+            .map(|x| {
+                (
+                    x.coefficient,
+                    x.op,
+                    &x.operand as &dyn std::fmt::Display,
+                    x.divisor,
+                )
+            })
+            .collect::<Vec<TermText>>();
+        write_affine(f, &self.coefficients, self.bias, &terms)
+    }
+}
+
+impl std::str::FromStr for SerializedExpr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        parse_affine(s, &|coefficients, bias, terms| Self {
+            coefficients,
+            bias,
+            terms: terms
+                .into_iter()
+                .map(|(coefficient, op, operand, divisor)| SerializedTerm {
+                    coefficient,
+                    op,
+                    operand,
+                    divisor,
+                })
+                .collect(),
+        })
+    }
+}
+
+#[cfg(feature = "serde")]
+impl Serialize for SerializedExpr {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> Deserialize<'de> for SerializedExpr {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let text = String::deserialize(deserializer)?;
+        text.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// Per-dimension indices and byte strides of a multi-dimensional access.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct SerializedSubscripts {
+    pub indices: Vec<SerializedExpr>,
+    pub strides: Vec<isize>,
+}
+
+/// A graph node whose edges are indices into [`SerializedGraph::nodes`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum SerializedNode {
+    Start {
+        next: Option<usize>,
+    },
+    End,
+    Access {
+        memref: usize,
+        offset: SerializedExpr,
+        subscripts: Option<SerializedSubscripts>,
+        kind: AccessKind,
+        next: Option<usize>,
+    },
+    Update {
+        ivar: usize,
+        expr: SerializedExpr,
+        next: Option<usize>,
+    },
+    Branch {
+        ivar: usize,
+        bound: SerializedExpr,
+        then: Option<usize>,
+        r#else: Option<usize>,
+    },
+}
+
+/// Flattened, owned graph: node `0` is the root and nodes are numbered in
+/// preorder. Shared successors and back-edges are plain repeated indices.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct SerializedGraph {
+    pub nodes: Vec<SerializedNode>,
+}
+
+/// Arena-free graph that can outlive the [`Context`] it was built in.
+pub type OwnedGraph = SerializedGraph;
+
+impl SerializedGraph {
+    /// Allocate the graph into `ctx`, returning its root. Returns `None` if
+    /// the graph is malformed, see [`Graph::rebuild_into`].
+    pub fn materialize<'a>(&self, ctx: &'a Context) -> Option<&'a Graph<'a>> {
+        Graph::rebuild_into(ctx, self)
+    }
+}
+
+/// Why [`Graph::from_json`] could not rebuild a graph.
+#[cfg(feature = "serde")]
+#[derive(Debug)]
+pub enum JsonError {
+    Parse(serde_json::Error),
+    /// The JSON is well-formed but describes an invalid graph, see
+    /// [`Graph::rebuild_into`].
+    Malformed,
+}
+
+#[cfg(feature = "serde")]
+impl std::fmt::Display for JsonError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            JsonError::Parse(e) => write!(f, "failed to parse graph: {e}"),
+            JsonError::Malformed => write!(f, "malformed graph"),
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+impl std::error::Error for JsonError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            JsonError::Parse(e) => Some(e),
+            JsonError::Malformed => None,
+        }
+    }
+}
+
+/// A flattened graph together with the reuse histograms of its access nodes,
+/// keyed by the same node indices.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct SerializedSimulation {
+    pub graph: SerializedGraph,
+    pub histograms: BTreeMap<usize, BTreeMap<usize, usize>>,
+}
+
+fn flatten_ids<'a>(g: &'a Graph<'a>) -> FxHashMap<NonNull<Graph<'a>>, usize> {
+    g.iter()
+        .enumerate()
+        .map(|(id, node)| (NonNull::from(node), id))
+        .collect()
+}
+
+impl<'a> Graph<'a> {
+    /// Flatten the reachable graph into an owned, id-based form.
+    pub fn serialize(&'a self) -> SerializedGraph {
+        let ids = flatten_ids(self);
+        let id = |x: Option<&'a Graph<'a>>| x.map(|x| ids[&NonNull::from(x)]);
+        let expr = SerializedExpr::new;
+        let nodes = self
+            .iter()
+            .map(|node| match *node {
+                Graph::Start(next) => SerializedNode::Start { next: id(next) },
+                Graph::End => SerializedNode::End,
+                Graph::Access {
+                    memref,
+                    offset,
+                    subscripts,
+                    kind,
+                    next,
+                } => SerializedNode::Access {
+                    memref,
+                    offset: expr(offset),
+                    subscripts: subscripts.map(|x| SerializedSubscripts {
+                        indices: x.indices().iter().map(|x| expr(x)).collect(),
+                        strides: x.strides().to_vec(),
+                    }),
+                    kind,
+                    next: id(next),
+                },
+                Graph::Update {
+                    ivar,
+                    expr: e,
+                    next,
+                } => SerializedNode::Update {
+                    ivar,
+                    expr: expr(e),
+                    next: id(next),
+                },
+                Graph::Branch {
+                    ivar,
+                    bound,
+                    then,
+                    r#else,
+                } => SerializedNode::Branch {
+                    ivar,
+                    bound: expr(bound),
+                    then: id(then),
+                    r#else: id(r#else),
+                },
+            })
+            .collect();
+        SerializedGraph { nodes }
+    }
+
+    /// Copy the graph out of its arena. Same as [`Graph::serialize`]; named
+    /// apart from `ToOwned::to_owned`, which `&Graph` also has.
+    pub fn to_owned_graph(&'a self) -> OwnedGraph {
+        self.serialize()
+    }
+
+    /// Reconstruct a flattened graph in `ctx`, returning its root. Returns
+    /// `None` if the graph is empty, an edge points outside `nodes`, an
+    /// access has a different number of indices and strides or a divisor is
+    /// not positive.
+    pub fn rebuild_into(ctx: &'a Context, graph: &SerializedGraph) -> Option<&'a Graph<'a>> {
+        let builder = GraphBuilder::new(ctx);
+        let expr = |x: &SerializedExpr| x.build(ctx);
+        let refs: Vec<GraphRef<'a>> = graph
+            .nodes
+            .iter()
+            .map(|node| {
+                Some(match node {
+                    SerializedNode::Start { .. } => builder.start(None),
+                    SerializedNode::End => builder.end(),
+                    SerializedNode::Access {
+                        memref,
+                        offset,
+                        subscripts: None,
+                        kind,
+                        ..
+                    } => builder.access(*memref, expr(offset)?, *kind, None),
+                    SerializedNode::Access {
+                        memref,
+                        subscripts: Some(subscripts),
+                        kind,
+                        ..
+                    } => {
+                        let indices = subscripts
+                            .indices
+                            .iter()
+                            .map(expr)
+                            .collect::<Option<Vec<_>>>()?;
+                        builder.access_nd(*memref, &indices, &subscripts.strides, *kind, None)?
+                    }
+                    SerializedNode::Update { ivar, expr: e, .. } => {
+                        builder.update(*ivar, expr(e)?, None)
+                    }
+                    SerializedNode::Branch { ivar, bound, .. } => {
+                        builder.branch(*ivar, expr(bound)?, None, None)
+                    }
+                })
+            })
+            .collect::<Option<_>>()?;
+        let get = |x: &Option<usize>| match x {
+            Some(x) => refs.get(*x).copied().map(Some),
+            None => Some(None),
+        };
+        // The nodes were just allocated and are not shared with the caller yet.
+        for (node, handle) in graph.nodes.iter().zip(refs.iter()) {
+            match node {
+                SerializedNode::Start { next }
+                | SerializedNode::Access { next, .. }
+                | SerializedNode::Update { next, .. } => {
+                    unsafe { handle.set_next(get(next)?) }.ok()?
+                }
+                SerializedNode::Branch { then, r#else, .. } => {
+                    unsafe { handle.set_then(get(then)?) }.ok()?;
+                    unsafe { handle.set_else(get(r#else)?) }.ok()?;
+                }
+                SerializedNode::End => (),
+            }
+        }
+        refs.first().map(|x| x.get())
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'a> Graph<'a> {
+    /// The flattened graph as JSON: a `nodes` array of variant-tagged nodes
+    /// whose edges are indices into it, starting from the root at `0`.
+    pub fn to_json(&'a self) -> String {
+        serde_json::to_string(&self.serialize()).unwrap()
+    }
+
+    /// Rebuild a graph written by [`Graph::to_json`] in `ctx`.
+    pub fn from_json(ctx: &'a Context, json: &str) -> Result<&'a Graph<'a>, JsonError> {
+        let graph: SerializedGraph = serde_json::from_str(json).map_err(JsonError::Parse)?;
+        Graph::rebuild_into(ctx, &graph).ok_or(JsonError::Malformed)
+    }
+}
+
+impl<'a> SimulationCtx<'a> {
+    /// Flatten `g` together with the histograms recorded for its access
+    /// nodes.
+    pub fn serialize(&self, g: &'a Graph<'a>) -> SerializedSimulation {
+        let histograms = g
+            .iter()
+            .enumerate()
+            .filter_map(|(id, node)| self.get_node_dist(node).map(|x| (id, x.clone())))
+            .collect();
+        SerializedSimulation {
+            graph: g.serialize(),
+            histograms,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expr_text_round_trips_extremes() {
+        let expr = SerializedExpr {
+            coefficients: vec![isize::MIN, isize::MAX, -1],
+            bias: isize::MIN,
+            terms: Vec::new(),
+        };
+        assert_eq!(expr.to_string().parse::<SerializedExpr>(), Ok(expr));
+        assert!("9223372036854775807 + 1".parse::<SerializedExpr>().is_err());
+        assert!("i0 - 9223372036854775809"
+            .parse::<SerializedExpr>()
+            .is_err());
+    }
+
+    #[test]
+    fn expr_text_rejects_huge_ivars() {
+        for text in ["i18446744073709551615", "i1000000000000", "2*i65536 + 1"] {
+            assert!(text.parse::<SerializedExpr>().is_err(), "{text}");
+        }
+        let last = "i65535".parse::<SerializedExpr>().unwrap();
+        assert_eq!(last.coefficients.len(), 65536);
+    }
+
+    #[test]
+    fn expr_text_round_trips_terms() {
+        let text = "2*i0 + 2*floordiv(i0 + 1, 4) - mod(floordiv(i1, 2), 8) + 3";
+        let expr = text.parse::<SerializedExpr>().unwrap();
+        assert_eq!(expr.terms.len(), 2);
+        assert_eq!(expr.terms[1].operand.terms[0].divisor, 2);
+        assert_eq!(expr.to_string(), text);
+        assert!("mod(i0, 0)".parse::<SerializedExpr>().is_err());
+        assert!("floordiv(i0)".parse::<SerializedExpr>().is_err());
+        let ctx = Context::with_capacity(4096);
+        let built = expr.build(&ctx).unwrap();
+        assert_eq!(SerializedExpr::new(built), expr);
+        assert_eq!(built.eval(&[-2, 5]), Ok(-4 - 2 - 2 + 3));
+    }
+
+    /// `for i0 in 0..8 { load m0[MIN*i0 + MIN]; if i1 < 1 { store } else
+    /// { store } }`, with both sides of the `if` on the same store node
+    /// when `share` is set and on two equal ones otherwise.
+    fn cyclic_graph(ctx: &Context, share: bool) -> &Graph<'_> {
+        let builder = GraphBuilder::new(ctx);
+        let cond = builder.branch(0, builder.expr(&[], 8), None, Some(builder.end()));
+        let step = builder.update(0, builder.expr(&[1], 1), Some(cond));
+        let offset = builder.expr(&[4], -3);
+        let store = builder.access(1, offset, AccessKind::Write, Some(step));
+        let other = if share {
+            store
+        } else {
+            builder.access(1, offset, AccessKind::Write, Some(step))
+        };
+        let pick = builder.branch(1, builder.expr(&[], 1), Some(store), Some(other));
+        let load = builder.access(
+            0,
+            builder.expr(&[isize::MIN], isize::MIN),
+            AccessKind::Read,
+            Some(pick),
+        );
+        unsafe { cond.set_then(Some(load)) }.unwrap();
+        let init = builder.update(0, builder.expr(&[], 0), Some(cond));
+        builder.start(Some(init)).get()
+    }
+
+    #[test]
+    fn rebuild_preserves_structure() {
+        let ctx = Context::with_capacity(4096);
+        let shared = cyclic_graph(&ctx, true);
+        let split = cyclic_graph(&ctx, false);
+        assert!(!shared.structurally_eq(split));
+        for graph in [shared, split] {
+            let rebuilt = Graph::rebuild_into(&ctx, &graph.to_owned_graph()).unwrap();
+            assert!(graph.structurally_eq(rebuilt));
+            assert_eq!(graph.structural_hash(), rebuilt.structural_hash());
+            #[cfg(feature = "serde")]
+            {
+                let parsed = Graph::from_json(&ctx, &graph.to_json()).unwrap();
+                assert!(graph.structurally_eq(parsed));
+            }
+        }
+    }
+}
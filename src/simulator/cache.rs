@@ -0,0 +1,223 @@
+use std::{collections::BTreeMap, ptr::NonNull};
+
+use rustc_hash::FxHashMap;
+
+use crate::graph::Graph;
+
+use super::{RunError, SimulationCtx};
+
+/// Which line of a full set is evicted on a miss.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReplacementPolicy {
+    /// Least recently used.
+    Lru,
+    /// Oldest inserted, regardless of hits.
+    Fifo,
+    /// Uniformly random, from a fixed seed so runs are reproducible.
+    Random,
+}
+
+/// Geometry of a set-associative cache. `sets * ways * block_size` is its
+/// capacity in bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CacheModel {
+    pub sets: usize,
+    pub ways: usize,
+    pub block_size: usize,
+    pub policy: ReplacementPolicy,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheCounters {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+}
+
+/// Result of [`SimulationCtx::simulate_cache`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub total: CacheCounters,
+    /// Counters of each access node, indexed by the node ids assigned in
+    /// `populate_node_info`.
+    pub nodes: Vec<CacheCounters>,
+}
+
+impl CacheStats {
+    /// Counters of the access node `g`.
+    pub fn node<'a>(&self, ctx: &SimulationCtx<'a>, g: &Graph<'a>) -> Option<&CacheCounters> {
+        ctx.address_map
+            .get(&NonNull::from(g))
+            .and_then(|x| self.nodes.get(*x))
+    }
+}
+
+/// Bytes filled into each level of a hierarchy, the misses of the level
+/// times its block size, from the `stats` that
+/// [`SimulationCtx::simulate_hierarchy`] returned for `levels`. The last
+/// entry is the traffic from DRAM; the model tracks no dirty lines, so
+/// write-backs are not counted.
+pub fn hierarchy_traffic(levels: &[CacheModel], stats: &[CacheStats]) -> Vec<u64> {
+    levels
+        .iter()
+        .zip(stats)
+        .map(|(model, stats)| stats.total.misses.saturating_mul(model.block_size as u64))
+        .collect()
+}
+
+#[derive(Debug)]
+pub(super) struct CacheState {
+    model: CacheModel,
+    /// `(block, stamp)` of each resident line; the stamp is the last access
+    /// time under LRU and the insertion time otherwise.
+    sets: Vec<Vec<(usize, u64)>>,
+    clock: u64,
+    rng: u64,
+    stats: CacheStats,
+}
+
+impl CacheState {
+    fn new(model: CacheModel, nodes: usize) -> Self {
+        assert!(model.sets > 0 && model.ways > 0 && model.block_size > 0);
+        Self {
+            model,
+            sets: vec![Vec::with_capacity(model.ways); model.sets],
+            clock: 0,
+            rng: 0x9e37_79b9_7f4a_7c15,
+            stats: CacheStats {
+                total: CacheCounters::default(),
+                nodes: vec![CacheCounters::default(); nodes],
+            },
+        }
+    }
+
+    fn next_random(&mut self) -> u64 {
+        // xorshift64
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        self.rng
+    }
+
+    /// Look up `address`, returning whether it hit.
+    pub(super) fn access(&mut self, node_id: usize, address: usize) -> bool {
+        let block = address / self.model.block_size;
+        let set = block % self.model.sets;
+        let time = self.clock;
+        self.clock += 1;
+        let counters = &mut self.stats.nodes[node_id];
+        if let Some(line) = self.sets[set].iter_mut().find(|x| x.0 == block) {
+            if self.model.policy == ReplacementPolicy::Lru {
+                line.1 = time;
+            }
+            counters.hits += 1;
+            self.stats.total.hits += 1;
+            return true;
+        }
+        counters.misses += 1;
+        self.stats.total.misses += 1;
+        if self.sets[set].len() < self.model.ways {
+            self.sets[set].push((block, time));
+            return false;
+        }
+        counters.evictions += 1;
+        self.stats.total.evictions += 1;
+        let victim = match self.model.policy {
+            ReplacementPolicy::Lru | ReplacementPolicy::Fifo => self.sets[set]
+                .iter()
+                .enumerate()
+                .min_by_key(|(_, x)| x.1)
+                .map(|(i, _)| i)
+                .unwrap(),
+            ReplacementPolicy::Random => self.next_random() as usize % self.model.ways,
+        };
+        self.sets[set][victim] = (block, time);
+        false
+    }
+}
+
+/// Fully-associative LRU cache of `capacity` blocks, fed the same block ids
+/// as the reuse histograms. An access hits iff its LRU stack distance is at
+/// most `capacity`, which makes it an oracle for the stack distance mode.
+#[derive(Debug)]
+pub(super) struct LruOracle {
+    capacity: usize,
+    /// Last access time of each resident block.
+    stamps: FxHashMap<usize, usize>,
+    /// Resident blocks by last access time, least recent first.
+    order: BTreeMap<usize, usize>,
+    pub(super) nodes: Vec<(u64, u64)>,
+}
+
+impl LruOracle {
+    pub(super) fn new(capacity: usize) -> Self {
+        assert!(capacity > 0, "cache capacity must be positive");
+        Self {
+            capacity,
+            stamps: FxHashMap::default(),
+            order: BTreeMap::new(),
+            nodes: Vec::new(),
+        }
+    }
+
+    /// Record `weight` back-to-back accesses to `block` at logic `time`:
+    /// all but the first always hit.
+    pub(super) fn access(&mut self, node_id: usize, block: usize, time: usize, weight: usize) {
+        let (hits, misses) = &mut self.nodes[node_id];
+        match self.stamps.insert(block, time) {
+            Some(last) => {
+                self.order.remove(&last);
+                *hits += weight as u64;
+            }
+            None => {
+                *misses += 1;
+                *hits += weight as u64 - 1;
+                if self.order.len() == self.capacity {
+                    let (_, victim) = self.order.pop_first().unwrap();
+                    self.stamps.remove(&victim);
+                }
+            }
+        }
+        self.order.insert(time, block);
+    }
+
+    pub(super) fn clear(&mut self) {
+        self.stamps.clear();
+        self.order.clear();
+        self.nodes.iter_mut().for_each(|x| *x = (0, 0));
+    }
+}
+
+impl<'a> SimulationCtx<'a> {
+    /// Run `graph` with the Rust interpreter through a set-associative cache
+    /// of the given geometry, counting hits, misses and evictions per node.
+    ///
+    /// Blocks are mapped to sets by `(address / block_size) % sets`. The
+    /// reuse histograms are recorded as in a normal [`SimulationCtx::run`].
+    pub fn simulate_cache(
+        &mut self,
+        graph: &'a Graph<'a>,
+        model: CacheModel,
+    ) -> Result<CacheStats, RunError> {
+        let mut stats = self.simulate_hierarchy(graph, &[model])?;
+        Ok(stats.pop().unwrap())
+    }
+
+    /// Like [`SimulationCtx::simulate_cache`] for an inclusive hierarchy:
+    /// every access goes to `levels[0]`, and an access that misses in a level
+    /// is presented to the next one. Each level computes block ids with its
+    /// own block size. Returns the stats of each level in order.
+    pub fn simulate_hierarchy(
+        &mut self,
+        graph: &'a Graph<'a>,
+        levels: &[CacheModel],
+    ) -> Result<Vec<CacheStats>, RunError> {
+        self.cache = levels
+            .iter()
+            .map(|x| CacheState::new(*x, self.node_info.len()))
+            .collect();
+        let result = self.run(graph);
+        let cache = std::mem::take(&mut self.cache);
+        result.map(|_| cache.into_iter().map(|x| x.stats).collect())
+    }
+}
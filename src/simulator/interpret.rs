@@ -0,0 +1,485 @@
+use std::ptr::NonNull;
+
+use crate::{
+    affine::{EvalError, Expr},
+    graph::Graph,
+};
+
+use super::{GraphChanged, SimulationCtx};
+
+/// Why [`SimulationCtx::run`] stopped before reaching an `End` node.
+#[derive(Debug, Clone, Copy)]
+pub enum RunError {
+    GraphChanged(GraphChanged),
+    Eval(EvalError),
+    /// An access names a memref that has no virtual address.
+    UnknownMemref(usize),
+    /// An access node that `populate_node_info` did not register.
+    UnregisteredNode,
+}
+
+impl std::fmt::Display for RunError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RunError::GraphChanged(e) => write!(f, "{e}"),
+            RunError::Eval(e) => write!(f, "{e}"),
+            RunError::UnknownMemref(memref) => write!(f, "memref {memref} has no address"),
+            RunError::UnregisteredNode => write!(f, "access node has no node id"),
+        }
+    }
+}
+
+impl std::error::Error for RunError {}
+
+impl From<GraphChanged> for RunError {
+    fn from(e: GraphChanged) -> Self {
+        RunError::GraphChanged(e)
+    }
+}
+
+impl From<EvalError> for RunError {
+    fn from(e: EvalError) -> Self {
+        RunError::Eval(e)
+    }
+}
+
+/// Evaluate `expr`, treating induction variables that were never updated as
+/// zero.
+fn eval(expr: &Expr, ivars: &mut Vec<isize>) -> Result<isize, EvalError> {
+    if ivars.len() < expr.ivar_count() {
+        ivars.resize(expr.ivar_count(), 0);
+    }
+    expr.eval(ivars)
+}
+
+/// A walk of a graph with the semantics of `slap_run_simulation` that pauses
+/// at every `Access`. A missing edge ends the walk like an `End` node.
+struct Cursor<'a> {
+    current: Option<&'a Graph<'a>>,
+    ivars: Vec<isize>,
+    /// Branches run since the last access.
+    idle: usize,
+}
+
+/// Where [`Cursor::step`] stopped.
+enum Step<'a> {
+    /// At an `Access` node, with its memref and offset.
+    Access(&'a Graph<'a>, usize, isize),
+    Done,
+    /// More branches than the limit ran in a row without an access.
+    Stalled,
+}
+
+impl<'a> Cursor<'a> {
+    fn new(graph: &'a Graph<'a>, ivars: Vec<isize>) -> Self {
+        Self {
+            current: Some(graph),
+            ivars,
+            idle: 0,
+        }
+    }
+
+    /// Walk up to and past the next access, returning it, or until the walk
+    /// ends or stalls.
+    fn step(&mut self, limit: Option<usize>) -> Result<Step<'a>, EvalError> {
+        while let Some(node) = self.current {
+            self.current = match node {
+                Graph::Start(next) => *next,
+                Graph::End => None,
+                Graph::Access {
+                    memref,
+                    offset,
+                    next,
+                    ..
+                } => {
+                    let offset = eval(offset, &mut self.ivars)?;
+                    self.current = *next;
+                    self.idle = 0;
+                    return Ok(Step::Access(node, *memref, offset));
+                }
+                Graph::Update { ivar, expr, next } => {
+                    let value = eval(expr, &mut self.ivars)?;
+                    if self.ivars.len() <= *ivar {
+                        self.ivars.resize(*ivar + 1, 0);
+                    }
+                    self.ivars[*ivar] = value;
+                    *next
+                }
+                Graph::Branch {
+                    ivar,
+                    bound,
+                    then,
+                    r#else,
+                } => {
+                    self.idle += 1;
+                    if limit.is_some_and(|x| self.idle > x) {
+                        return Ok(Step::Stalled);
+                    }
+                    let bound = eval(bound, &mut self.ivars)?;
+                    if self.ivars.get(*ivar).copied().unwrap_or(0) < bound {
+                        *then
+                    } else {
+                        *r#else
+                    }
+                }
+            };
+        }
+        Ok(Step::Done)
+    }
+}
+
+/// Walk `graph` from `ivars`, calling `access(node, memref, offset)` at every
+/// `Access` until it returns `Ok(false)`. With a `limit`, the walk also ends
+/// once `limit` branches in a row ran without an access, and returns
+/// `Ok(true)` to tell it stalled.
+fn interpret<'a>(
+    graph: &'a Graph<'a>,
+    ivars: Vec<isize>,
+    limit: Option<usize>,
+    mut access: impl FnMut(&'a Graph<'a>, usize, isize) -> Result<bool, RunError>,
+) -> Result<bool, RunError> {
+    let mut cursor = Cursor::new(graph, ivars);
+    loop {
+        match cursor.step(limit)? {
+            Step::Access(node, memref, offset) => {
+                if !access(node, memref, offset)? {
+                    return Ok(false);
+                }
+            }
+            Step::Done => return Ok(false),
+            Step::Stalled => return Ok(true),
+        }
+    }
+}
+
+/// How [`SimulationCtx::run_streams`] merges the accesses of its walks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Interleave<'s> {
+    /// One access from each walk that has not ended, in turn.
+    RoundRobin,
+    /// The `k`-th access is made by walk `schedule[k % schedule.len()]`
+    /// (modulo the number of walks), or by the next walk in order that has
+    /// not ended if that one has. An empty schedule is round-robin.
+    Schedule(&'s [usize]),
+}
+
+impl<'a> SimulationCtx<'a> {
+    /// Simulate `graph` by interpreting it in Rust, without the native JIT.
+    ///
+    /// Follows the same semantics as `slap_run_simulation`: `Update` stores
+    /// its expression into the induction variable, `Branch` takes `then` while
+    /// `ivar < bound`, and `Access` touches `offset + vaddr` of its memref. A
+    /// missing edge ends the walk like an `End` node, and so does reaching
+    /// either limit of `with_max_logic_time`.
+    pub fn run(&mut self, graph: &'a Graph<'a>) -> Result<(), RunError> {
+        self.ensure_unchanged(graph)?;
+        let limit = self.max_logic_time;
+        let stalled = interpret(graph, Vec::new(), limit, |node, memref, offset| {
+            self.interpreted_access(node, memref, offset)
+        })?;
+        self.timed_out |= stalled;
+        self.merge_partitions();
+        self.flush_dense();
+        Ok(())
+    }
+
+    /// Simulate several walks of `graph` through one cache, such as the
+    /// threads of a parallel loop. Walk `k` starts with induction variable
+    /// `j` at `constants[k][j]`, as in `access_trace`, and the accesses of
+    /// the walks are merged into one stream by `interleave`. The walks share
+    /// node ids, so reuse across walks lands in the same histograms.
+    ///
+    /// Otherwise follows [`SimulationCtx::run`]. A walk that stalls under
+    /// `with_max_logic_time` ends on its own and sets `did_time_out`;
+    /// reaching the logic-time limit ends every walk.
+    pub fn run_streams(
+        &mut self,
+        graph: &'a Graph<'a>,
+        constants: &[&[isize]],
+        interleave: Interleave<'_>,
+    ) -> Result<(), RunError> {
+        self.ensure_unchanged(graph)?;
+        let limit = self.max_logic_time;
+        let mut cursors = constants
+            .iter()
+            .map(|x| Some(Cursor::new(graph, x.to_vec())))
+            .collect::<Vec<_>>();
+        let count = cursors.len();
+        let mut live = count;
+        let mut accesses = 0;
+        let mut last = count.saturating_sub(1);
+        while live != 0 {
+            let start = match interleave {
+                Interleave::Schedule(schedule) if !schedule.is_empty() => {
+                    schedule[accesses % schedule.len()]
+                }
+                _ => last + 1,
+            };
+            let id = (0..count)
+                .map(|x| (start % count + x) % count)
+                .find(|x| cursors[*x].is_some())
+                .unwrap();
+            match cursors[id].as_mut().unwrap().step(limit)? {
+                Step::Access(node, memref, offset) => {
+                    if !self.interpreted_access(node, memref, offset)? {
+                        break;
+                    }
+                    accesses += 1;
+                    last = id;
+                }
+                Step::Done => {
+                    cursors[id] = None;
+                    live -= 1;
+                }
+                Step::Stalled => {
+                    self.timed_out = true;
+                    cursors[id] = None;
+                    live -= 1;
+                }
+            }
+        }
+        self.merge_partitions();
+        self.flush_dense();
+        Ok(())
+    }
+
+    /// Touch `offset` of `memref` for the access node `node`, see
+    /// `access_address`.
+    fn interpreted_access(
+        &mut self,
+        node: &'a Graph<'a>,
+        memref: usize,
+        offset: isize,
+    ) -> Result<bool, RunError> {
+        let vaddr = self
+            .vaddrs
+            .get(memref)
+            .ok_or(RunError::UnknownMemref(memref))?;
+        let address = (offset as usize).wrapping_add(*vaddr);
+        let node_id = *self
+            .address_map
+            .get(&NonNull::from(node))
+            .ok_or(RunError::UnregisteredNode)?;
+        Ok(unsafe { self.access_address(node_id, address) })
+    }
+
+    /// The `(memref, block)` pairs `graph` touches, in order, with induction
+    /// variable `k` starting at `constants[k]` (zero past its end). Blocks are
+    /// named by their start address relative to the base address, at the
+    /// block size of each memref, as in `access_address`. Stops at the limits
+    /// of `with_max_logic_time` if they are set.
+    pub fn access_trace(
+        &self,
+        graph: &'a Graph<'a>,
+        constants: &[isize],
+    ) -> Result<Vec<(usize, usize)>, RunError> {
+        let mut trace = Vec::new();
+        let limit = self.max_logic_time;
+        interpret(graph, constants.to_vec(), limit, |_, memref, offset| {
+            if limit.is_some_and(|x| trace.len() >= x) {
+                return Ok(false);
+            }
+            let vaddr = self
+                .vaddrs
+                .get(memref)
+                .ok_or(RunError::UnknownMemref(memref))?;
+            let address = (offset as usize)
+                .wrapping_add(*vaddr)
+                .wrapping_sub(self.base_addr);
+            let block_size = self.get_memref_block_size(memref);
+            trace.push((memref, address & !(block_size - 1)));
+            Ok(true)
+        })?;
+        Ok(trace)
+    }
+
+    /// Whether `a` and `b` touch the same `(memref, block)` sequence when
+    /// started from the same `constants`, see `access_trace`. Node identity
+    /// plays no part, so this checks that a transformation preserved the
+    /// trace exactly.
+    pub fn traces_equivalent(
+        &self,
+        a: &'a Graph<'a>,
+        b: &'a Graph<'a>,
+        constants: &[isize],
+    ) -> Result<bool, RunError> {
+        Ok(self.access_trace(a, constants)? == self.access_trace(b, constants)?)
+    }
+
+    /// Like [`SimulationCtx::traces_equivalent`], but only requires the same
+    /// accesses the same number of times in any order, for transformations
+    /// such as loop interchange that reorder the trace.
+    pub fn traces_equivalent_unordered(
+        &self,
+        a: &'a Graph<'a>,
+        b: &'a Graph<'a>,
+        constants: &[isize],
+    ) -> Result<bool, RunError> {
+        let mut a = self.access_trace(a, constants)?;
+        let mut b = self.access_trace(b, constants)?;
+        a.sort_unstable();
+        b.sort_unstable();
+        Ok(a == b)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        affine::{QuasiOp, QuasiTerm},
+        builder::{GraphBuilder, LoopNestBuilder},
+        graph::AccessKind,
+        simulator::{DistanceMode, SimulationCtx},
+        Context,
+    };
+
+    #[test]
+    fn missing_memref_is_an_error() {
+        let ctx = Context::with_capacity(4096);
+        let builder = GraphBuilder::new(&ctx);
+        let access = builder.access(
+            1,
+            builder.expr(&[], 0),
+            AccessKind::Read,
+            Some(builder.end()),
+        );
+        let graph = builder.start(Some(access)).get();
+        let vaddrs = [0];
+        let mut sctx = SimulationCtx::new(&ctx, 64, &vaddrs, DistanceMode::LogicalTime).unwrap();
+        sctx.populate_node_info(graph);
+        assert!(matches!(sctx.run(graph), Err(RunError::UnknownMemref(1))));
+    }
+
+    /// Both orders of a `rows` by `cols` loop nest over a row-major array of
+    /// 64-byte rows, as `(row-major, column-major)` traversals.
+    fn both_orders(ctx: &Context, rows: isize, cols: isize) -> (&Graph<'_>, &Graph<'_>) {
+        let builder = GraphBuilder::new(ctx);
+        let nest = |outer: isize, inner: isize, offset: &[isize]| {
+            LoopNestBuilder::new(ctx)
+                .for_loop(builder.expr(&[], 0), builder.expr(&[], outer), 1)
+                .for_loop(builder.expr(&[], 0), builder.expr(&[], inner), 1)
+                .load(0, builder.expr(offset, 0))
+                .build()
+        };
+        (nest(rows, cols, &[64, 8]), nest(cols, rows, &[8, 64]))
+    }
+
+    #[test]
+    fn interchange_preserves_accesses_not_order() {
+        let ctx = Context::with_capacity(1 << 14);
+        let (rows, cols) = both_orders(&ctx, 4, 3);
+        let vaddrs = [0];
+        let sctx = SimulationCtx::new(&ctx, 8, &vaddrs, DistanceMode::LogicalTime).unwrap();
+        let copy = rows.deep_clone_into(&ctx);
+        assert!(sctx.traces_equivalent(rows, copy, &[]).unwrap());
+        assert!(!sctx.traces_equivalent(rows, cols, &[]).unwrap());
+        assert!(sctx.traces_equivalent_unordered(rows, cols, &[]).unwrap());
+        let (fewer, _) = both_orders(&ctx, 3, 3);
+        assert!(!sctx.traces_equivalent_unordered(rows, fewer, &[]).unwrap());
+        assert_eq!(sctx.access_trace(rows, &[]).unwrap().len(), 12);
+    }
+
+    #[test]
+    fn quasi_offsets_and_bounds() {
+        let ctx = Context::with_capacity(1 << 14);
+        let builder = GraphBuilder::new(&ctx);
+        // `for i0 in 0..16 floordiv 2 { load m0[8 * (offset mod 4)] }`
+        let nest = |offset: &[isize], bias: isize| {
+            let term = QuasiTerm {
+                coefficient: 8,
+                op: QuasiOp::Mod,
+                operand: builder.expr(offset, bias),
+                divisor: 4,
+            };
+            let offset = Expr::with_terms(&ctx, &[], 0, &[term]).unwrap();
+            let bound = builder.floordiv(builder.expr(&[], 16), 2).unwrap();
+            LoopNestBuilder::new(&ctx)
+                .for_loop(builder.expr(&[], 0), bound, 1)
+                .load(0, ctx.arena.alloc(offset))
+                .build()
+        };
+        let (shifted, plain) = (nest(&[1], -8), nest(&[1], 0));
+        let vaddrs = [0];
+        let sctx = SimulationCtx::new(&ctx, 8, &vaddrs, DistanceMode::LogicalTime).unwrap();
+        // Only a floored `mod` keeps `i0 - 8` in `0..4`.
+        assert!(sctx.traces_equivalent(shifted, plain, &[]).unwrap());
+        let trace = sctx.access_trace(plain, &[]).unwrap();
+        assert_eq!(trace.len(), 8);
+        assert_eq!(trace[5], (0, 8));
+        // The bound is not affine, so the trip count is unknown.
+        assert_eq!(plain.estimate_trip_counts()[&0], None);
+    }
+
+    #[test]
+    fn access_free_cycle_stops_at_the_limit() {
+        let ctx = Context::with_capacity(4096);
+        let builder = GraphBuilder::new(&ctx);
+        // `load m0[0]; while i0 < 1 { i1 = i1 + 1 }`, which never ends.
+        let cond = builder.branch(0, builder.expr(&[], 1), None, Some(builder.end()));
+        let spin = builder.update(1, builder.expr(&[0, 1], 1), Some(cond));
+        unsafe { cond.set_then(Some(spin)) }.unwrap();
+        let access = builder.access(0, builder.expr(&[], 0), AccessKind::Read, Some(cond));
+        let graph = builder.start(Some(access)).get();
+        let vaddrs = [0];
+        let mut sctx = SimulationCtx::new(&ctx, 64, &vaddrs, DistanceMode::LogicalTime)
+            .unwrap()
+            .with_max_logic_time(100);
+        assert_eq!(sctx.access_trace(graph, &[]).unwrap(), [(0, 0)]);
+        sctx.populate_node_info(graph);
+        sctx.run(graph).unwrap();
+        assert!(sctx.did_time_out());
+        assert_eq!(sctx.current_time(), 1);
+    }
+
+    #[test]
+    fn streams_share_the_cache() {
+        let ctx = Context::with_capacity(4096);
+        let builder = GraphBuilder::new(&ctx);
+        // `for i0 in i1..i1 + 4 { load m0[64 * i0] }`, one block per
+        // iteration, with two walks starting two blocks apart.
+        let graph = LoopNestBuilder::new(&ctx)
+            .for_loop(builder.expr(&[0, 1], 0), builder.expr(&[0, 1], 4), 1)
+            .load(0, builder.expr(&[64], 0))
+            .build();
+        let access = graph
+            .iter()
+            .find(|x| matches!(x, Graph::Access { .. }))
+            .unwrap();
+        let vaddrs = [0];
+        let streams: [&[isize]; 2] = [&[0, 0], &[0, 2]];
+        let run = |interleave| {
+            let mut sctx =
+                SimulationCtx::new(&ctx, 64, &vaddrs, DistanceMode::LogicalTime).unwrap();
+            sctx.populate_node_info(graph);
+            sctx.run_streams(graph, &streams, interleave).unwrap();
+            let stats = sctx.get_node_stats(access).unwrap();
+            (stats, sctx.get_node_dist(access).unwrap().clone())
+        };
+        // Blocks 2 and 3 are touched by both walks: three accesses apart when
+        // the walks alternate, two apart when the first runs ahead.
+        let (stats, dist) = run(Interleave::RoundRobin);
+        assert_eq!((stats.total_accesses, stats.cold_misses), (8, 6));
+        assert_eq!(dist.into_iter().collect::<Vec<_>>(), [(3, 2)]);
+        let (stats, dist) = run(Interleave::Schedule(&[0]));
+        assert_eq!((stats.total_accesses, stats.cold_misses), (8, 6));
+        assert_eq!(dist.into_iter().collect::<Vec<_>>(), [(2, 2)]);
+        assert_eq!(
+            run(Interleave::Schedule(&[])).1,
+            run(Interleave::RoundRobin).1
+        );
+    }
+
+    #[test]
+    fn constants_bind_free_ivars() {
+        let ctx = Context::with_capacity(4096);
+        let builder = GraphBuilder::new(&ctx);
+        // i1 is never updated, so it acts as a parameter.
+        let access = builder.access(0, builder.expr(&[0, 64], 0), AccessKind::Read, None);
+        let graph = builder.start(Some(access)).get();
+        let vaddrs = [0];
+        let sctx = SimulationCtx::new(&ctx, 64, &vaddrs, DistanceMode::LogicalTime).unwrap();
+        assert_eq!(sctx.access_trace(graph, &[]).unwrap(), [(0, 0)]);
+        assert_eq!(sctx.access_trace(graph, &[0, 3]).unwrap(), [(0, 192)]);
+    }
+}
@@ -1,80 +1,953 @@
-use std::{cell::UnsafeCell, collections::BTreeMap, ptr::NonNull};
+use std::{
+    cell::UnsafeCell,
+    collections::BTreeMap,
+    hash::{Hash, Hasher},
+    ptr::NonNull,
+};
 
 use rustc_hash::{FxHashMap, FxHashSet};
 
-use crate::graph::Graph;
+use crate::{
+    affine::Expr,
+    graph::{AccessKind, Graph},
+    visitor::{GraphVisitor, Walk},
+};
+
+pub mod cache;
+mod interpret;
+mod parallel;
+#[cfg(feature = "arrow")]
+mod parquet;
+mod quantile;
+mod splay;
+
+pub use interpret::{Interleave, RunError};
+pub use quantile::P2Quantile;
+pub use splay::StackDistance;
+
+/// What the reuse histograms measure.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DistanceMode {
+    /// Logic time elapsed since the previous access to the same block.
+    #[default]
+    LogicalTime,
+    /// LRU stack distance: distinct blocks touched since the previous access
+    /// to the same block, the block itself included. An access hits in a
+    /// fully-associative LRU cache of `n` blocks iff its distance is `<= n`.
+    StackDistance,
+}
+
+/// Reuse distance of an access to `block` at `time`, or `None` on the first
+/// touch of the block.
+fn reuse_distance(
+    mode: DistanceMode,
+    access_time: &mut FxHashMap<usize, usize>,
+    stack_distance: &mut StackDistance,
+    block: usize,
+    time: usize,
+) -> Option<usize> {
+    match mode {
+        DistanceMode::LogicalTime => {
+            let last_access = access_time.entry(block).or_insert(time);
+            if *last_access != time {
+                let interval = time - *last_access;
+                *last_access = time;
+                Some(interval)
+            } else {
+                None
+            }
+        }
+        DistanceMode::StackDistance => stack_distance.access(block),
+    }
+}
+
+/// How per-node reuse intervals are recorded.
+#[derive(Debug, Clone, Default)]
+pub enum HistogramConfig {
+    /// Keep the full interval histogram of every node.
+    #[default]
+    Exact,
+    /// Only keep a P² estimator per requested quantile, using O(1) memory per
+    /// node instead of a growing histogram. See [`P2Quantile`] for accuracy.
+    StreamingQuantiles { quantiles: Vec<f64> },
+    /// Count intervals below `limit` in a flat array per node and only put
+    /// longer ones in the histogram map. The arrays are folded into the
+    /// histograms when a run finishes, so queries see the same result as
+    /// under `Exact`.
+    Dense { limit: usize },
+}
+
+/// Track reuse for a fixed, hash-selected subset of blocks only, so memory
+/// grows with the sampled footprint rather than the whole trace.
+///
+/// Roughly one in `period` blocks is sampled, and every access to a sampled
+/// block is counted `period` times. Under [`DistanceMode::StackDistance`] the
+/// distances, measured among sampled blocks only, are scaled by `period` as
+/// well. Results are estimates whose error grows with `period` and shrinks
+/// with the number of distinct blocks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SamplingConfig {
+    pub period: usize,
+}
+
+impl SamplingConfig {
+    fn contains(&self, block_id: usize) -> bool {
+        // splitmix64 finalizer, so neighbouring blocks are sampled
+        // independently.
+        let mut x = block_id as u64;
+        x = (x ^ (x >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        x = (x ^ (x >> 27)).wrapping_mul(0x94d049bb133111eb);
+        x ^= x >> 31;
+        x.is_multiple_of(self.period as u64)
+    }
+}
+
+/// A block size that is zero or not a power of two.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InvalidBlockSize(pub usize);
+
+impl std::fmt::Display for InvalidBlockSize {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "block size {} is not a power of two", self.0)
+    }
+}
+
+impl std::error::Error for InvalidBlockSize {}
+
+fn check_block_size(block_size: usize) -> Result<usize, InvalidBlockSize> {
+    if block_size.is_power_of_two() {
+        Ok(block_size)
+    } else {
+        Err(InvalidBlockSize(block_size))
+    }
+}
+
+/// The graph was rewired after `populate_node_info`, so the node ids assigned
+/// there no longer describe what the native walker would traverse.
+#[derive(Debug, Clone, Copy)]
+pub struct GraphChanged;
+
+impl std::fmt::Display for GraphChanged {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "graph was modified after populate_node_info")
+    }
+}
+
+impl std::error::Error for GraphChanged {}
+
+/// Per-node counters that the reuse histogram alone does not capture.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NodeStats {
+    /// Number of times the node was executed.
+    pub total_accesses: u64,
+    /// Accesses with no prior touch of their block, which produce no
+    /// reuse interval.
+    pub cold_misses: u64,
+}
+
+/// Estimated memory traffic of one access node at a given cache size, see
+/// `SimulationCtx::top_k_by_traffic`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TrafficRow {
+    /// The node id assigned by `populate_node_info`.
+    pub node_id: usize,
+    pub memref: usize,
+    pub accesses: u64,
+    /// Cold misses plus reuses with an interval longer than the cache size.
+    pub misses: u64,
+    /// `misses` times the block size of the memref.
+    pub bytes: u64,
+}
+
+/// Owned reuse histogram of one access node, detached from the arena.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NodeHistogram {
+    /// The node id assigned by `populate_node_info`.
+    pub node_id: usize,
+    pub memref: usize,
+    pub histogram: BTreeMap<usize, usize>,
+}
+
+/// Owned copy of simulation results that can be sent across threads and
+/// outlives the [`crate::Context`] it was simulated in.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SimulationSnapshot {
+    pub nodes: Vec<NodeHistogram>,
+}
+
+impl SimulationSnapshot {
+    /// Add the histograms of `other` into `self`, matching nodes by id and
+    /// keeping them ordered. Both snapshots must come from simulations of the
+    /// same graph.
+    pub fn merge(&mut self, other: &SimulationSnapshot) {
+        for node in &other.nodes {
+            match self
+                .nodes
+                .binary_search_by_key(&node.node_id, |x| x.node_id)
+            {
+                Ok(i) => parallel::merge_histograms(&mut self.nodes[i].histogram, &node.histogram),
+                Err(i) => self.nodes.insert(i, node.clone()),
+            }
+        }
+    }
+}
+
+/// Distinct blocks touched within the last `window` units of logic time.
+#[derive(Debug)]
+struct WorkingSet {
+    window: usize,
+    recent: std::collections::VecDeque<(usize, usize)>,
+    counts: FxHashMap<usize, usize>,
+    dist: BTreeMap<usize, usize>,
+}
+
+impl WorkingSet {
+    fn access(&mut self, block_id: usize, time: usize, weight: usize) {
+        self.recent.push_back((block_id, time));
+        *self.counts.entry(block_id).or_insert(0) += 1;
+        while let Some((block, start)) = self.recent.front().copied() {
+            if start + self.window > time {
+                break;
+            }
+            self.recent.pop_front();
+            if let std::collections::hash_map::Entry::Occupied(mut entry) = self.counts.entry(block)
+            {
+                *entry.get_mut() -= 1;
+                if *entry.get() == 0 {
+                    entry.remove();
+                }
+            }
+        }
+        *self.dist.entry(self.counts.len()).or_insert(0) += weight;
+    }
+    fn clear(&mut self) {
+        self.recent.clear();
+        self.counts.clear();
+        self.dist.clear();
+    }
+}
+
+/// Splits block reuse into temporal reuse, of an address touched before,
+/// and spatial reuse, of another address in a block touched before. Temporal
+/// reuse records the logic time since the previous access to the address,
+/// spatial reuse the time since the previous access to the block.
+#[derive(Debug, Default)]
+struct LocalityTrack {
+    /// Logic time of the last access to each block.
+    last_access: FxHashMap<usize, usize>,
+    /// Logic time of the last access to each address.
+    addresses: FxHashMap<usize, usize>,
+    spatial: Vec<BTreeMap<usize, usize>>,
+    temporal: Vec<BTreeMap<usize, usize>>,
+}
+
+impl LocalityTrack {
+    fn access(&mut self, node_id: usize, address: usize, block_id: usize, time: usize) {
+        let last_block = self.last_access.insert(block_id, time);
+        let (dist, last_time) = match (self.addresses.insert(address, time), last_block) {
+            (Some(last_time), _) => (&mut self.temporal, last_time),
+            (None, Some(last_time)) => (&mut self.spatial, last_time),
+            (None, None) => return,
+        };
+        *dist[node_id].entry(time - last_time).or_insert(0) += 1;
+    }
+    fn clear(&mut self) {
+        self.last_access.clear();
+        self.addresses.clear();
+        self.spatial.iter_mut().for_each(BTreeMap::clear);
+        self.temporal.iter_mut().for_each(BTreeMap::clear);
+    }
+}
+
+/// Separate reuse of blocks by reads and by writes: a read records the logic
+/// time since the block was last read, a write the time since it was last
+/// written. A `ReadWrite` access counts as both.
+#[derive(Debug, Default)]
+struct ReadWriteTrack {
+    last_read: FxHashMap<usize, usize>,
+    last_write: FxHashMap<usize, usize>,
+    read: Vec<BTreeMap<usize, usize>>,
+    write: Vec<BTreeMap<usize, usize>>,
+}
+
+impl ReadWriteTrack {
+    fn access(
+        &mut self,
+        node_id: usize,
+        kind: AccessKind,
+        block_id: usize,
+        time: usize,
+        weight: usize,
+    ) {
+        let sides = [
+            (kind.is_read(), &mut self.last_read, &mut self.read),
+            (kind.is_write(), &mut self.last_write, &mut self.write),
+        ];
+        for (_, last, dist) in sides.into_iter().filter(|x| x.0) {
+            if let Some(last_time) = last.insert(block_id, time) {
+                *dist[node_id].entry(time - last_time).or_insert(0) += weight;
+            }
+        }
+    }
+    fn clear(&mut self) {
+        self.last_read.clear();
+        self.last_write.clear();
+        self.read.iter_mut().for_each(BTreeMap::clear);
+        self.write.iter_mut().for_each(BTreeMap::clear);
+    }
+}
+
+/// Reuse histograms in which every observation fades with its age, see
+/// [`SimulationCtx::with_decay`].
+#[derive(Debug)]
+struct DecayTrack {
+    lambda: f64,
+    /// Weights are stored relative to this logic time: an observation at
+    /// time `t` is stored as `exp(lambda * (t - reference))`, so storing
+    /// never has to touch older entries. The reference moves forward, and
+    /// the stored weights are rescaled, before they could overflow.
+    reference: usize,
+    node_info: Vec<BTreeMap<usize, f64>>,
+}
+
+impl DecayTrack {
+    /// Largest exponent stored before rescaling, well below the overflow of
+    /// `f64::exp` at about 709.
+    const MAX_EXPONENT: f64 = 256.0;
+
+    fn observe(&mut self, node_id: usize, interval: usize, time: usize, weight: usize) {
+        let mut exponent = self.lambda * (time - self.reference) as f64;
+        if exponent > Self::MAX_EXPONENT {
+            let factor = (-exponent).exp();
+            for dist in self.node_info.iter_mut() {
+                dist.values_mut().for_each(|x| *x *= factor);
+            }
+            self.reference = time;
+            exponent = 0.0;
+        }
+        *self.node_info[node_id].entry(interval).or_insert(0.0) += weight as f64 * exponent.exp();
+    }
+
+    /// The histogram of `node_id` with every observation weighted by
+    /// `exp(-lambda * (now - t))`.
+    fn dist(&self, node_id: usize, now: usize) -> BTreeMap<usize, f64> {
+        let factor = (-self.lambda * (now - self.reference) as f64).exp();
+        self.node_info[node_id]
+            .iter()
+            .map(|(interval, weight)| (*interval, weight * factor))
+            .collect()
+    }
+
+    fn clear(&mut self) {
+        self.reference = 0;
+        self.node_info.iter_mut().for_each(BTreeMap::clear);
+    }
+}
+
+/// Callback installed by [`SimulationCtx::with_access_hook`].
+struct AccessHook<'a>(Box<dyn FnMut(usize, usize, Option<usize>) + 'a>);
+
+impl std::fmt::Debug for AccessHook<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("AccessHook")
+    }
+}
+
+/// Reuse tracking for one additional block size, sharing the logic clock of
+/// the primary simulation.
+#[derive(Debug)]
+struct BlockSizeTrack {
+    block_size: usize,
+    access_time: FxHashMap<usize, usize>,
+    stack_distance: StackDistance,
+    node_info: Vec<BTreeMap<usize, usize>>,
+}
+
+impl BlockSizeTrack {
+    unsafe fn access(&mut self, mode: DistanceMode, node_id: usize, address: usize, time: usize) {
+        let block_id = address / self.block_size;
+        if let Some(interval) = reuse_distance(
+            mode,
+            &mut self.access_time,
+            &mut self.stack_distance,
+            block_id,
+            time,
+        ) {
+            self.node_info
+                .get_unchecked_mut(node_id)
+                .entry(interval)
+                .and_modify(|e| *e += 1)
+                .or_insert(1);
+        }
+    }
+}
+
+/// Registers every access node [`Graph::walk`] reaches, in walk order.
+struct NodeRegistrar<'s, 'a>(&'s mut SimulationCtx<'a>);
+
+impl<'a> GraphVisitor<'a> for NodeRegistrar<'_, 'a> {
+    fn visit_access(
+        &mut self,
+        node: &'a Graph<'a>,
+        memref: usize,
+        _offset: &'a Expr<'a>,
+        kind: AccessKind,
+    ) -> Walk {
+        self.0.register_access(node, memref, kind);
+        Walk::Continue
+    }
+}
 
 #[derive(Debug)]
 pub struct SimulationCtx<'a> {
     block_size: usize,
+    base_addr: usize,
+    memref_block_size: FxHashMap<usize, usize>,
     vaddrs: &'a [usize],
     logic_time: usize,
+    distance_mode: DistanceMode,
+    histogram_config: HistogramConfig,
     pub(crate) node_info: bumpalo::collections::Vec<'a, BTreeMap<usize, usize>>,
     pub(crate) address_map: FxHashMap<NonNull<Graph<'a>>, usize>,
     access_time: FxHashMap<usize, usize>,
+    stack_distance: StackDistance,
+    node_quantiles: Vec<Box<[P2Quantile]>>,
+    node_dense: Vec<Vec<u32>>,
+    access_count: Vec<u64>,
+    /// Accesses to sampled blocks, scaled by the sampling period like the
+    /// histograms they are compared against. Only counted with
+    /// `with_sampling`.
+    sampled_count: Vec<u64>,
+    cold_only: bool,
+    node_memref: Vec<usize>,
+    node_kind: Vec<AccessKind>,
+    cold_misses: Vec<u64>,
+    cold_blocks: FxHashMap<usize, FxHashSet<usize>>,
+    fingerprint: Option<u64>,
+    extra_block_sizes: Vec<BlockSizeTrack>,
+    partitions: Option<FxHashMap<usize, Vec<parallel::Event>>>,
+    max_logic_time: Option<usize>,
+    timed_out: bool,
+    cache: Vec<cache::CacheState>,
+    working_set: Option<WorkingSet>,
+    locality: Option<LocalityTrack>,
+    read_write: Option<ReadWriteTrack>,
+    on_access: Option<AccessHook<'a>>,
+    sampling: Option<SamplingConfig>,
+    lru: Option<cache::LruOracle>,
+    canonical_ids: bool,
+    decay: Option<DecayTrack>,
 }
 
 impl<'a> SimulationCtx<'a> {
     unsafe fn access(&mut self, node_id: usize, block_id: usize) {
+        self.access_weighted(node_id, block_id, 1);
+    }
+    /// Record an access that stands for `weight` dynamic accesses: the logic
+    /// clock advances by `weight` and every counter it lands in grows by
+    /// `weight`. A zero weight records nothing.
+    unsafe fn access_weighted(&mut self, node_id: usize, block_id: usize, weight: usize) {
+        if weight == 0 {
+            return;
+        }
+        if self.max_logic_time.is_some_and(|x| self.logic_time >= x) {
+            self.timed_out = true;
+            return;
+        }
         let time = self.logic_time;
-        self.logic_time += 1;
-        let node_info = self.node_info.get_unchecked_mut(node_id);
-        let last_access = self.access_time.entry(block_id).or_insert(time);
-        if *last_access != time {
-            let interval = time - *last_access;
-            node_info
-                .entry(interval)
-                .and_modify(|e| *e += 1)
-                .or_insert(1);
-            *last_access = time;
+        self.logic_time += weight;
+        *self.access_count.get_unchecked_mut(node_id) += weight as u64;
+        if let Some(working_set) = &mut self.working_set {
+            working_set.access(block_id, time, weight);
+        }
+        if let Some(lru) = &mut self.lru {
+            lru.access(node_id, block_id, time, weight);
+        }
+        if let Some(read_write) = &mut self.read_write {
+            let kind = *self.node_kind.get_unchecked(node_id);
+            read_write.access(node_id, kind, block_id, time, weight);
+        }
+        if self.cold_only {
+            let memref = *self.node_memref.get_unchecked(node_id);
+            if self.cold_blocks.entry(memref).or_default().insert(block_id) {
+                *self.cold_misses.get_unchecked_mut(node_id) += weight as u64;
+            }
+            return;
+        }
+        let weight = match self.sampling {
+            Some(sampling) if !sampling.contains(block_id) => return,
+            Some(sampling) => {
+                *self.sampled_count.get_unchecked_mut(node_id) += (weight * sampling.period) as u64;
+                weight * sampling.period
+            }
+            None => weight,
+        };
+        if let Some(partitions) = &mut self.partitions {
+            if self.distance_mode == DistanceMode::LogicalTime
+                && matches!(self.histogram_config, HistogramConfig::Exact)
+            {
+                let memref = *self.node_memref.get_unchecked(node_id);
+                partitions.entry(memref).or_default().push(parallel::Event {
+                    node_id,
+                    block_id,
+                    time,
+                    weight,
+                });
+                return;
+            }
+        }
+        let mut interval = reuse_distance(
+            self.distance_mode,
+            &mut self.access_time,
+            &mut self.stack_distance,
+            block_id,
+            time,
+        );
+        if let (Some(sampling), DistanceMode::StackDistance) = (self.sampling, self.distance_mode) {
+            interval = interval.map(|x| x * sampling.period);
+        }
+        if let Some(hook) = &mut self.on_access {
+            (hook.0)(node_id, block_id, interval);
+        }
+        let Some(interval) = interval else {
+            *self.cold_misses.get_unchecked_mut(node_id) += weight as u64;
+            return;
+        };
+        if let Some(decay) = &mut self.decay {
+            decay.observe(node_id, interval, time, weight);
+        }
+        match self.histogram_config {
+            HistogramConfig::Exact => {
+                *self
+                    .node_info
+                    .get_unchecked_mut(node_id)
+                    .entry(interval)
+                    .or_insert(0) += weight;
+            }
+            HistogramConfig::Dense { limit } => {
+                let dense = self.node_dense.get_unchecked_mut(node_id);
+                if interval < limit && dense.len() <= interval {
+                    dense.resize(interval + 1, 0);
+                }
+                match dense.get_mut(interval) {
+                    Some(slot) if *slot as usize + weight <= u32::MAX as usize => {
+                        *slot += weight as u32;
+                    }
+                    _ => {
+                        *self
+                            .node_info
+                            .get_unchecked_mut(node_id)
+                            .entry(interval)
+                            .or_insert(0) += weight;
+                    }
+                }
+            }
+            HistogramConfig::StreamingQuantiles { .. } => {
+                for estimator in self.node_quantiles.get_unchecked_mut(node_id).iter_mut() {
+                    for _ in 0..weight {
+                        estimator.observe(interval as f64);
+                    }
+                }
+            }
         }
     }
-    pub fn new(ctx: &'a crate::Context, block_size: usize, vaddrs: &'a [usize]) -> Self {
-        Self {
-            block_size,
+    /// Record an access to a byte address: the primary simulation sees it at
+    /// the block size of the node's memref, and every extra block size gets
+    /// its own block id at the same logic time.
+    ///
+    /// Addresses are taken relative to the base set by `with_base_addr`, with
+    /// wrapping subtraction. The primary simulation identifies a block by
+    /// the relative address it starts at, `address & !(b - 1)` for block size
+    /// `b`, so memrefs with different block sizes never share a block by
+    /// accident; an extra block size `b` uses `address / b`. The caches of
+    /// `simulate_hierarchy` see the same relative addresses.
+    ///
+    /// Returns `false` once the logic-time limit is reached and the walk
+    /// should stop.
+    unsafe fn access_address(&mut self, node_id: usize, address: usize) -> bool {
+        if self.max_logic_time.is_some_and(|x| self.logic_time >= x) {
+            self.timed_out = true;
+            return false;
+        }
+        let address = address.wrapping_sub(self.base_addr);
+        for level in self.cache.iter_mut() {
+            if level.access(node_id, address) {
+                break;
+            }
+        }
+        let time = self.logic_time;
+        for track in self.extra_block_sizes.iter_mut() {
+            track.access(self.distance_mode, node_id, address, time);
+        }
+        let memref = *self.node_memref.get_unchecked(node_id);
+        let block_size = self.get_memref_block_size(memref);
+        let block = address & !(block_size - 1);
+        if let Some(locality) = &mut self.locality {
+            locality.access(node_id, address, block, time);
+        }
+        self.access(node_id, block);
+        true
+    }
+    /// Fails if `block_size` is zero or not a power of two.
+    pub fn new(
+        ctx: &'a crate::Context,
+        block_size: usize,
+        vaddrs: &'a [usize],
+        distance_mode: DistanceMode,
+    ) -> Result<Self, InvalidBlockSize> {
+        Ok(Self {
+            block_size: check_block_size(block_size)?,
+            base_addr: 0,
+            memref_block_size: FxHashMap::default(),
             vaddrs,
             logic_time: 0,
+            distance_mode,
+            histogram_config: HistogramConfig::Exact,
             node_info: bumpalo::collections::Vec::new_in(&ctx.arena),
             address_map: FxHashMap::default(),
             access_time: FxHashMap::default(),
+            stack_distance: StackDistance::default(),
+            node_quantiles: Vec::new(),
+            node_dense: Vec::new(),
+            access_count: Vec::new(),
+            sampled_count: Vec::new(),
+            cold_only: false,
+            node_memref: Vec::new(),
+            node_kind: Vec::new(),
+            cold_misses: Vec::new(),
+            cold_blocks: FxHashMap::default(),
+            fingerprint: None,
+            extra_block_sizes: Vec::new(),
+            partitions: None,
+            max_logic_time: None,
+            timed_out: false,
+            cache: Vec::new(),
+            working_set: None,
+            locality: None,
+            read_write: None,
+            on_access: None,
+            sampling: None,
+            lru: None,
+            canonical_ids: false,
+            decay: None,
+        })
+    }
+    /// Select how reuse intervals are recorded. Must be called before
+    /// `populate_node_info`.
+    pub fn with_histogram_config(mut self, config: HistogramConfig) -> Self {
+        self.histogram_config = config;
+        self
+    }
+    /// Only track reuse for the blocks selected by `config`, see
+    /// [`SamplingConfig`]. Cold-only mode and the tracks added by
+    /// `with_block_sizes` are not sampled.
+    pub fn with_sampling(mut self, config: SamplingConfig) -> Self {
+        assert!(config.period > 0, "sampling period must be positive");
+        self.sampling = Some(config);
+        self
+    }
+    /// Fraction of blocks whose reuse is tracked: `1 / period` with
+    /// `with_sampling`, `1` otherwise. Histogram counts are already scaled
+    /// back by its inverse.
+    pub fn sampling_rate(&self) -> f64 {
+        self.sampling.map_or(1.0, |x| 1.0 / x.period as f64)
+    }
+    /// Measure addresses from `base_addr` instead of from zero, so that
+    /// block boundaries line up with a trace whose base is not block-aligned,
+    /// see `access_address`. Accesses recorded by block id are unaffected.
+    pub fn with_base_addr(mut self, base_addr: usize) -> Self {
+        self.base_addr = base_addr;
+        self
+    }
+    /// Additionally track reuse intervals at each of `block_sizes` in the same
+    /// pass. Must be called before `populate_node_info`.
+    ///
+    /// Fails if a block size is zero or not a power of two.
+    pub fn with_block_sizes(mut self, block_sizes: &[usize]) -> Result<Self, InvalidBlockSize> {
+        self.extra_block_sizes = block_sizes
+            .iter()
+            .filter(|x| **x != self.block_size)
+            .map(|x| {
+                Ok(BlockSizeTrack {
+                    block_size: check_block_size(*x)?,
+                    access_time: FxHashMap::default(),
+                    stack_distance: StackDistance::default(),
+                    node_info: Vec::new(),
+                })
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(self)
+    }
+    /// Only count compulsory misses: each access is checked against the set
+    /// of blocks its memref has already touched, and all interval and
+    /// histogram bookkeeping is skipped.
+    pub fn with_cold_only(mut self) -> Self {
+        self.cold_only = true;
+        self
+    }
+    /// Defer reuse tracking of each memref to its own partition, analysed on
+    /// separate threads by `merge_partitions`. Only logic-time intervals with
+    /// exact histograms are partitioned; other configurations are simulated
+    /// serially as usual.
+    ///
+    /// The accesses of a run are buffered until merged, trading memory for
+    /// parallelism.
+    pub fn with_parallel_memrefs(mut self) -> Self {
+        self.partitions = Some(FxHashMap::default());
+        self
+    }
+    /// Call `hook(node_id, block_id, interval)` whenever an access is
+    /// recorded, with `None` as the interval of a cold miss, e.g. to feed an
+    /// online estimator. A weighted access calls it once. For accesses by
+    /// address, `block_id` is the start address of the block.
+    ///
+    /// The hook is an indirect call on the hot path of every access and
+    /// keeps the compiler from inlining the histogram update around it, so
+    /// expect a noticeable slowdown for cheap hooks already. Accesses that
+    /// are deferred by `with_parallel_memrefs` or only counted by
+    /// `with_cold_only` do not reach it.
+    pub fn with_access_hook(mut self, hook: impl FnMut(usize, usize, Option<usize>) + 'a) -> Self {
+        self.on_access = Some(AccessHook(Box::new(hook)));
+        self
+    }
+    /// Also record, at every access, how many distinct blocks were touched in
+    /// the last `window` units of logic time (the access itself included).
+    pub fn with_working_set_window(mut self, window: usize) -> Self {
+        self.working_set = Some(WorkingSet {
+            window,
+            recent: Default::default(),
+            counts: FxHashMap::default(),
+            dist: BTreeMap::new(),
+        });
+        self
+    }
+    /// How often each working-set size was observed, see
+    /// `with_working_set_window`. Empty if no window was configured.
+    pub fn working_set_dist(&self) -> BTreeMap<usize, usize> {
+        self.working_set
+            .as_ref()
+            .map(|x| x.dist.clone())
+            .unwrap_or_default()
+    }
+    /// Also keep a time-decayed histogram per node, in which a reuse
+    /// observed at logic time `t` counts `exp(-lambda * (now - t))` when read
+    /// at time `now`, see `get_node_weighted_dist`. The plain histograms are
+    /// recorded as before. Accesses deferred by `with_parallel_memrefs` are
+    /// not included. Must be called before `populate_node_info`.
+    pub fn with_decay(mut self, lambda: f64) -> Self {
+        assert!(
+            lambda >= 0.0 && lambda.is_finite(),
+            "decay rate must be non-negative"
+        );
+        self.decay = Some(DecayTrack {
+            lambda,
+            reference: 0,
+            node_info: Vec::new(),
+        });
+        self
+    }
+    /// Number access nodes by memref, then by a hash of their offset and by
+    /// access kind, instead of in discovery order, so structurally equal
+    /// graphs get the same node ids however they were built. Nodes that
+    /// compare equal keep their discovery order. Nodes already registered by
+    /// an earlier `populate_node_info` keep their ids. Must be called before
+    /// `populate_node_info`.
+    pub fn with_canonical_ids(mut self) -> Self {
+        self.canonical_ids = true;
+        self
+    }
+    /// Also count, per node, the hits and misses of a fully-associative LRU
+    /// cache holding `capacity` blocks of the primary block size, updated as
+    /// the trace runs. Must be called before `populate_node_info`.
+    pub fn with_lru_cache(mut self, capacity: usize) -> Self {
+        self.lru = Some(cache::LruOracle::new(capacity));
+        self
+    }
+    /// Additionally split the reuse of each block into temporal and spatial
+    /// reuse, see `get_node_temporal_dist` and `get_node_spatial_dist`. Only
+    /// accesses made by address are classified. Must be called before
+    /// `populate_node_info`.
+    pub fn with_spatial_tracking(mut self) -> Self {
+        self.locality = Some(LocalityTrack::default());
+        self
+    }
+    /// Additionally record the reuse of blocks by reads and by writes apart,
+    /// see `get_node_read_dist` and `get_node_write_dist`. Intervals are in
+    /// logic time whatever the distance mode, and every access counts, as
+    /// for `with_lru_cache`. Must be called before `populate_node_info`.
+    pub fn with_read_write_tracking(mut self) -> Self {
+        self.read_write = Some(ReadWriteTrack::default());
+        self
+    }
+    /// Stop the simulation once `limit` accesses have been recorded, or once
+    /// `limit` branches in a row have run without an access, so a graph
+    /// whose loops never reach their bound cannot run forever, even if such
+    /// a loop has no access in it.
+    pub fn with_max_logic_time(mut self, limit: usize) -> Self {
+        self.max_logic_time = Some(limit);
+        self
+    }
+    /// Whether the last run was cut short by the limit set with
+    /// `with_max_logic_time`.
+    pub fn did_time_out(&self) -> bool {
+        self.timed_out
+    }
+    /// Fold the flat arrays of [`HistogramConfig::Dense`] into the histogram
+    /// maps. Called at the end of every run.
+    pub(crate) fn flush_dense(&mut self) {
+        for (dense, dist) in self.node_dense.iter_mut().zip(self.node_info.iter_mut()) {
+            for (interval, count) in dense.iter().enumerate().filter(|(_, x)| **x != 0) {
+                *dist.entry(interval).or_insert(0) += *count as usize;
+            }
+            dense.clear();
         }
     }
-    fn populate_node_info_impl(
+    /// Logic time of the next access, i.e. the number of accesses recorded
+    /// so far.
+    pub fn current_time(&self) -> usize {
+        self.logic_time
+    }
+    /// Logic time of the most recent access to `block_id` at the primary
+    /// block size, where an address-based access uses the start address of
+    /// its block as the id, see `access_address`. Only tracked in [`DistanceMode::LogicalTime`], and only
+    /// after `merge_partitions` when memrefs are analysed in parallel.
+    pub fn last_access(&self, block_id: usize) -> Option<usize> {
+        self.access_time.get(&block_id).copied()
+    }
+    /// Clear everything recorded by previous simulations while keeping the
+    /// node ids assigned by `populate_node_info`, so another trace can be run
+    /// on the same graph.
+    pub fn reset_counters(&mut self) {
+        self.logic_time = 0;
+        self.timed_out = false;
+        self.access_time.clear();
+        self.stack_distance.clear();
+        self.node_info.iter_mut().for_each(BTreeMap::clear);
+        self.access_count.iter_mut().for_each(|x| *x = 0);
+        self.sampled_count.iter_mut().for_each(|x| *x = 0);
+        self.cold_misses.iter_mut().for_each(|x| *x = 0);
+        self.cold_blocks.clear();
+        self.node_dense.iter_mut().for_each(Vec::clear);
+        if let Some(working_set) = &mut self.working_set {
+            working_set.clear();
+        }
+        if let Some(read_write) = &mut self.read_write {
+            read_write.clear();
+        }
+        if let Some(locality) = &mut self.locality {
+            locality.clear();
+        }
+        if let Some(lru) = &mut self.lru {
+            lru.clear();
+        }
+        if let Some(decay) = &mut self.decay {
+            decay.clear();
+        }
+        for estimators in self.node_quantiles.iter_mut() {
+            for estimator in estimators.iter_mut() {
+                *estimator = P2Quantile::new(estimator.quantile());
+            }
+        }
+        for track in self.extra_block_sizes.iter_mut() {
+            track.access_time.clear();
+            track.stack_distance.clear();
+            track.node_info.iter_mut().for_each(BTreeMap::clear);
+        }
+    }
+    /// Replace the base virtual address of each memref for the next run.
+    pub fn set_vaddrs(&mut self, vaddrs: &'a [usize]) {
+        self.vaddrs = vaddrs;
+    }
+    /// Override the block size used to compute block ids for `memref`.
+    ///
+    /// Memrefs without an override fall back to the global block size. Fails
+    /// if `block_size` is zero or not a power of two.
+    pub fn set_memref_block_size(
         &mut self,
-        g: &'a Graph<'a>,
-        visited: &mut FxHashSet<NonNull<Graph<'a>>>,
-    ) {
-        if !visited.insert(NonNull::from(g)) {
+        memref: usize,
+        block_size: usize,
+    ) -> Result<(), InvalidBlockSize> {
+        self.memref_block_size
+            .insert(memref, check_block_size(block_size)?);
+        Ok(())
+    }
+    pub fn get_memref_block_size(&self, memref: usize) -> usize {
+        self.memref_block_size
+            .get(&memref)
+            .copied()
+            .unwrap_or(self.block_size)
+    }
+    /// Assign the next node id to the access node `g` unless it has one.
+    fn register_access(&mut self, g: &'a Graph<'a>, memref: usize, kind: AccessKind) {
+        let nonnull = NonNull::from(g);
+        if self.address_map.contains_key(&nonnull) {
             return;
         }
-        match g {
-            Graph::Start(Some(x)) => self.populate_node_info_impl(x, visited),
-            Graph::Access { next, .. } => {
-                let nonnull = NonNull::from(g);
-                self.address_map.entry(nonnull).or_insert_with(|| {
-                    let res = self.node_info.len();
-                    self.node_info.push(Default::default());
-                    res
-                });
-                if let Some(x) = next {
-                    self.populate_node_info_impl(x, visited);
-                }
-            }
-            Graph::Update { next: Some(x), .. } => self.populate_node_info_impl(x, visited),
-            Graph::Branch {
-                then: Some(x),
-                r#else: Some(y),
-                ..
-            } => {
-                self.populate_node_info_impl(x, visited);
-                self.populate_node_info_impl(y, visited);
+        let res = self.node_info.len();
+        self.node_info.push(Default::default());
+        self.access_count.push(0);
+        self.sampled_count.push(0);
+        self.node_memref.push(memref);
+        self.node_kind.push(kind);
+        self.cold_misses.push(0);
+        self.node_dense.push(Vec::new());
+        if let Some(lru) = &mut self.lru {
+            lru.nodes.push((0, 0));
+        }
+        if let Some(decay) = &mut self.decay {
+            decay.node_info.push(BTreeMap::new());
+        }
+        if let Some(read_write) = &mut self.read_write {
+            read_write.read.push(Default::default());
+            read_write.write.push(Default::default());
+        }
+        if let Some(locality) = &mut self.locality {
+            locality.spatial.push(Default::default());
+            locality.temporal.push(Default::default());
+        }
+        for track in self.extra_block_sizes.iter_mut() {
+            track.node_info.push(Default::default());
+        }
+        if let HistogramConfig::StreamingQuantiles { quantiles } = &self.histogram_config {
+            self.node_quantiles
+                .push(quantiles.iter().copied().map(P2Quantile::new).collect());
+        }
+        self.address_map.insert(nonnull, res);
+    }
+    pub fn populate_node_info(&mut self, g: &'a Graph<'a>) {
+        if self.canonical_ids {
+            let mut nodes = g
+                .iter()
+                .filter_map(|x| match x {
+                    Graph::Access {
+                        memref,
+                        offset,
+                        kind,
+                        ..
+                    } => {
+                        let mut hasher = rustc_hash::FxHasher::default();
+                        offset.hash(&mut hasher);
+                        Some(((*memref, hasher.finish(), *kind as u8), x, *kind))
+                    }
+                    _ => None,
+                })
+                .collect::<Vec<_>>();
+            nodes.sort_by_key(|x| x.0);
+            for ((memref, ..), node, kind) in nodes {
+                self.register_access(node, memref, kind);
             }
-            _ => (),
         }
+        let _ = g.walk(&mut NodeRegistrar(self));
+        self.fingerprint = Some(g.fingerprint());
     }
 
-    pub fn populate_node_info(&mut self, g: &'a Graph<'a>) {
-        self.populate_node_info_impl(g, &mut FxHashSet::default());
+    /// Check that `g` has the same structure it had when `populate_node_info`
+    /// last ran, so node ids seen by the simulation match the histograms.
+    pub fn ensure_unchanged(&self, g: &Graph<'a>) -> Result<(), GraphChanged> {
+        match self.fingerprint {
+            Some(fingerprint) if fingerprint == g.fingerprint() => Ok(()),
+            _ => Err(GraphChanged),
+        }
     }
 
     pub fn get_node_dist(&self, g: &Graph<'a>) -> Option<&BTreeMap<usize, usize>> {
@@ -82,8 +955,415 @@ impl<'a> SimulationCtx<'a> {
             .get(&NonNull::from(g))
             .map(|x| &self.node_info[*x])
     }
+
+    /// Copy of the histogram `g` has accumulated so far, including counts
+    /// still held in dense storage. Safe to call in the middle of a run, e.g.
+    /// from the native side between accesses; accesses buffered by
+    /// `with_parallel_memrefs` only show up after `merge_partitions`.
+    pub fn current_node_dist(&self, g: &Graph<'a>) -> Option<BTreeMap<usize, usize>> {
+        let id = *self.address_map.get(&NonNull::from(g))?;
+        let mut dist = self.node_info[id].clone();
+        if let Some(dense) = self.node_dense.get(id) {
+            for (interval, count) in dense.iter().enumerate().filter(|(_, x)| **x != 0) {
+                *dist.entry(interval).or_insert(0) += *count as usize;
+            }
+        }
+        Some(dist)
+    }
+
+    /// Sum of the reuse histograms of `nodes`. Nodes that were never
+    /// registered by `populate_node_info` are skipped.
+    pub fn merged_dist(&self, nodes: &[&Graph<'a>]) -> BTreeMap<usize, usize> {
+        let mut result = BTreeMap::new();
+        for dist in nodes.iter().filter_map(|x| self.get_node_dist(x)) {
+            parallel::merge_histograms(&mut result, dist);
+        }
+        result
+    }
+
+    /// Sum of the reuse histograms of every access node touching `memref`.
+    pub fn get_memref_dist(&self, memref: usize) -> BTreeMap<usize, usize> {
+        let mut result = BTreeMap::new();
+        for (dist, _) in self
+            .node_info
+            .iter()
+            .zip(self.node_memref.iter())
+            .filter(|(_, x)| **x == memref)
+        {
+            parallel::merge_histograms(&mut result, dist);
+        }
+        result
+    }
+
+    /// Sum of the reuse histograms of every registered node.
+    pub fn merged_dist_all(&self) -> BTreeMap<usize, usize> {
+        let mut result = BTreeMap::new();
+        for dist in self.node_info.iter() {
+            parallel::merge_histograms(&mut result, dist);
+        }
+        result
+    }
+
+    /// Time-decayed reuse histogram of `g` as of the current logic time.
+    /// Requires `with_decay`.
+    pub fn get_node_weighted_dist(&self, g: &Graph<'a>) -> Option<BTreeMap<usize, f64>> {
+        let id = *self.address_map.get(&NonNull::from(g))?;
+        Some(self.decay.as_ref()?.dist(id, self.logic_time))
+    }
+
+    /// `(hits, misses)` of `g` in the cache set up by `with_lru_cache`.
+    pub fn get_node_hit_miss(&self, g: &Graph<'a>) -> Option<(u64, u64)> {
+        let id = *self.address_map.get(&NonNull::from(g))?;
+        self.lru.as_ref().map(|x| x.nodes[id])
+    }
+
+    /// Logic-time intervals of the accesses of `g` to an address touched
+    /// before, measured since that address was last touched. Requires
+    /// `with_spatial_tracking`.
+    pub fn get_node_temporal_dist(&self, g: &Graph<'a>) -> Option<&BTreeMap<usize, usize>> {
+        let id = *self.address_map.get(&NonNull::from(g))?;
+        self.locality.as_ref().map(|x| &x.temporal[id])
+    }
+
+    /// Logic-time intervals of the accesses of `g` to a first-touched address
+    /// in a block touched before, measured since the block was last touched.
+    /// Requires `with_spatial_tracking`.
+    pub fn get_node_spatial_dist(&self, g: &Graph<'a>) -> Option<&BTreeMap<usize, usize>> {
+        let id = *self.address_map.get(&NonNull::from(g))?;
+        self.locality.as_ref().map(|x| &x.spatial[id])
+    }
+
+    /// Logic-time intervals since the block was last read, of the reads made
+    /// by `g` (`Read` or `ReadWrite`). Requires `with_read_write_tracking`.
+    pub fn get_node_read_dist(&self, g: &Graph<'a>) -> Option<&BTreeMap<usize, usize>> {
+        let id = *self.address_map.get(&NonNull::from(g))?;
+        let read_write = self.read_write.as_ref()?;
+        self.node_kind[id].is_read().then(|| &read_write.read[id])
+    }
+
+    /// Logic-time intervals since the block was last written, of the writes
+    /// made by `g` (`Write` or `ReadWrite`). Requires
+    /// `with_read_write_tracking`.
+    pub fn get_node_write_dist(&self, g: &Graph<'a>) -> Option<&BTreeMap<usize, usize>> {
+        let id = *self.address_map.get(&NonNull::from(g))?;
+        let read_write = self.read_write.as_ref()?;
+        self.node_kind[id].is_write().then(|| &read_write.write[id])
+    }
+
+    /// Reuse histogram of `g` when blocks are `block_size` bytes, for the
+    /// primary block size or any size passed to `with_block_sizes`.
+    pub fn get_node_dist_for_block(
+        &self,
+        g: &Graph<'a>,
+        block_size: usize,
+    ) -> Option<&BTreeMap<usize, usize>> {
+        if block_size == self.block_size {
+            return self.get_node_dist(g);
+        }
+        let id = self.address_map.get(&NonNull::from(g))?;
+        self.extra_block_sizes
+            .iter()
+            .find(|x| x.block_size == block_size)
+            .map(|x| &x.node_info[*id])
+    }
+
+    /// Number of times the access node `g` was executed, including accesses
+    /// that did not produce a reuse interval.
+    pub fn node_access_count(&self, g: &Graph<'a>) -> Option<u64> {
+        self.address_map
+            .get(&NonNull::from(g))
+            .map(|x| self.access_count[*x])
+    }
+
+    /// Number of accesses of node `id` its histogram accounts for: every
+    /// access, or under `with_sampling` the scaled accesses to sampled
+    /// blocks, so that cold misses are never negative.
+    fn histogram_total(&self, id: usize) -> u64 {
+        match self.sampling {
+            Some(_) => self.sampled_count[id],
+            None => self.access_count[id],
+        }
+    }
+
+    /// Miss ratio of `node` for each fully-associative cache size (in blocks)
+    /// in `sizes`. Reuse intervals longer than the size count as misses, as
+    /// do accesses that never had a prior touch of their block.
+    ///
+    /// Requires exact histograms; a node that was never registered or never
+    /// executed yields an empty curve. Under `with_sampling` the ratios are
+    /// taken over the accesses to sampled blocks.
+    pub fn miss_ratio_curve(&self, node: &Graph<'a>, sizes: &[usize]) -> Vec<f64> {
+        match self.address_map.get(&NonNull::from(node)) {
+            Some(id) => miss_ratio_curve(
+                std::iter::once((&self.node_info[*id], self.histogram_total(*id))),
+                sizes,
+            ),
+            None => Vec::new(),
+        }
+    }
+
+    /// Fraction of the accesses of `g`, cold misses included, whose reuse
+    /// interval is at most `cache_blocks`, or `None` if `g` never executed
+    /// (never touched a sampled block, under `with_sampling`).
+    ///
+    /// This is the exact hit rate of a fully-associative LRU cache of
+    /// `cache_blocks` blocks only under [`DistanceMode::StackDistance`].
+    /// Logic-time intervals also count accesses to the same block, not
+    /// distinct blocks, so under [`DistanceMode::LogicalTime`] the result is
+    /// a pessimistic approximation.
+    pub fn predicted_hit_rate(&self, g: &Graph<'a>, cache_blocks: usize) -> Option<f64> {
+        let id = *self.address_map.get(&NonNull::from(g))?;
+        if self.access_count[id] == 0 {
+            return None;
+        }
+        let curve = miss_ratio_curve(
+            std::iter::once((&self.node_info[id], self.histogram_total(id))),
+            &[cache_blocks],
+        );
+        if curve.is_empty() {
+            return None;
+        }
+        Some(1.0 - curve[0])
+    }
+
+    /// Miss-ratio curve over the accesses of every registered node.
+    pub fn aggregate_miss_ratio_curve(&self, sizes: &[usize]) -> Vec<f64> {
+        miss_ratio_curve(
+            self.node_info
+                .iter()
+                .enumerate()
+                .map(|(id, dist)| (dist, self.histogram_total(id))),
+            sizes,
+        )
+    }
+
+    /// Smallest fully-associative cache size, in blocks, whose aggregate miss
+    /// ratio is at most `target`, or `None` if nothing ran or the cold misses
+    /// alone exceed it. Inverts `aggregate_miss_ratio_curve`, with the same
+    /// caveats about the distance mode as `predicted_hit_rate`.
+    pub fn cache_size_for_miss_rate(&self, target: f64) -> Option<usize> {
+        // The curve only drops at sizes equal to a recorded interval.
+        let mut sizes = self
+            .node_info
+            .iter()
+            .flat_map(|x| x.keys().copied())
+            .chain([0])
+            .collect::<Vec<_>>();
+        sizes.sort_unstable();
+        sizes.dedup();
+        let curve = self.aggregate_miss_ratio_curve(&sizes);
+        sizes
+            .into_iter()
+            .zip(curve)
+            .find(|(_, x)| *x <= target)
+            .map(|(size, _)| size)
+    }
+
+    /// The `k` access nodes that move the most bytes through a
+    /// fully-associative cache of `cache_size` blocks, largest first, ties
+    /// broken by node id. Misses are estimated from the miss-ratio curve, so
+    /// under `with_sampling` they are scaled up from the sampled blocks.
+    pub fn top_k_by_traffic(&self, k: usize, cache_size: usize) -> Vec<TrafficRow> {
+        let mut rows = self
+            .node_info
+            .iter()
+            .enumerate()
+            .filter(|(id, _)| self.access_count[*id] != 0)
+            .map(|(id, dist)| {
+                let memref = self.node_memref[id];
+                let accesses = self.access_count[id];
+                let ratio = miss_ratio_curve(
+                    std::iter::once((dist, self.histogram_total(id))),
+                    &[cache_size],
+                )
+                .first()
+                .copied()
+                .unwrap_or(1.0);
+                let misses = (ratio * accesses as f64).round() as u64;
+                TrafficRow {
+                    node_id: id,
+                    memref,
+                    accesses,
+                    misses,
+                    bytes: misses.saturating_mul(self.get_memref_block_size(memref) as u64),
+                }
+            })
+            .collect::<Vec<_>>();
+        rows.sort_by(|x, y| y.bytes.cmp(&x.bytes).then(x.node_id.cmp(&y.node_id)));
+        rows.truncate(k);
+        rows
+    }
+
+    /// Number of accesses made by `g` that were the first touch of their
+    /// block. In cold-only mode first touches are tracked per memref.
+    pub fn node_cold_misses(&self, g: &Graph<'a>) -> Option<u64> {
+        self.address_map
+            .get(&NonNull::from(g))
+            .map(|x| self.cold_misses[*x])
+    }
+
+    /// Access and cold-miss counts of the access node `g`.
+    pub fn get_node_stats(&self, g: &Graph<'a>) -> Option<NodeStats> {
+        let id = *self.address_map.get(&NonNull::from(g))?;
+        Some(NodeStats {
+            total_accesses: self.access_count[id],
+            cold_misses: self.cold_misses[id],
+        })
+    }
+
+    /// Number of distinct blocks touched through `memref`. Only recorded in
+    /// cold-only mode.
+    pub fn memref_footprint(&self, memref: usize) -> usize {
+        self.cold_blocks.get(&memref).map_or(0, |x| x.len())
+    }
+
+    /// Every access node registered by `populate_node_info` with its reuse
+    /// histogram, ordered by node id.
+    pub fn node_dists(
+        &self,
+    ) -> impl Iterator<Item = (&'a Graph<'a>, &BTreeMap<usize, usize>)> + '_ {
+        let mut nodes = self.address_map.iter().collect::<Vec<_>>();
+        nodes.sort_unstable_by_key(|x| *x.1);
+        nodes
+            .into_iter()
+            .map(|(node, id)| (unsafe { node.as_ref() }, &self.node_info[*id]))
+    }
+
+    /// Copy the histogram of every node, ordered by node id.
+    pub fn snapshot(&self) -> SimulationSnapshot {
+        SimulationSnapshot {
+            nodes: self
+                .node_info
+                .iter()
+                .enumerate()
+                .map(|(id, dist)| NodeHistogram {
+                    node_id: id,
+                    memref: self.node_memref[id],
+                    histogram: dist.clone(),
+                })
+                .collect(),
+        }
+    }
+
+    /// Write every node's reuse histogram as CSV rows of `node_id, memref,
+    /// interval, count`, ordered by node id and then interval. Nodes without
+    /// any recorded interval get a single `count` of 0 under an empty
+    /// `interval` if `include_empty` is set, and are skipped otherwise.
+    pub fn write_histograms_csv(
+        &self,
+        writer: &mut impl std::io::Write,
+        include_empty: bool,
+    ) -> std::io::Result<()> {
+        writeln!(writer, "node_id,memref,interval,count")?;
+        for (id, dist) in self.node_info.iter().enumerate() {
+            let memref = self.node_memref[id];
+            if dist.is_empty() && include_empty {
+                writeln!(writer, "{id},{memref},,0")?;
+            }
+            for (interval, count) in dist {
+                writeln!(writer, "{id},{memref},{interval},{count}")?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Mean reuse interval of `g`, or `None` if it recorded no interval.
+    pub fn node_mean_interval(&self, g: &Graph<'a>) -> Option<f64> {
+        let dist = self.get_node_dist(g)?;
+        let total: usize = dist.values().sum();
+        if total == 0 {
+            return None;
+        }
+        let sum: f64 = dist.iter().map(|(k, v)| *k as f64 * *v as f64).sum();
+        Some(sum / total as f64)
+    }
+
+    /// The `p`-th percentile (`p` in `[0, 1]`) of the reuse intervals of `g`,
+    /// linearly interpolated between the two closest ranks and rounded to
+    /// the nearest interval.
+    pub fn node_percentile_interval(&self, g: &Graph<'a>, p: f64) -> Option<usize> {
+        let dist = self.get_node_dist(g)?;
+        let total: usize = dist.values().sum();
+        if total == 0 {
+            return None;
+        }
+        let rank = p.clamp(0.0, 1.0) * (total - 1) as f64;
+        let at = |rank: usize| {
+            let mut seen = 0;
+            dist.iter()
+                .find_map(|(interval, count)| {
+                    seen += count;
+                    (seen > rank).then_some(*interval as f64)
+                })
+                .unwrap()
+        };
+        let lower = at(rank.floor() as usize);
+        let upper = at(rank.ceil() as usize);
+        Some((lower + (upper - lower) * rank.fract()).round() as usize)
+    }
+
+    /// Estimate the `q`-quantile of the reuse intervals recorded for `g`.
+    ///
+    /// Under [`HistogramConfig::Exact`] and [`HistogramConfig::Dense`] this is
+    /// the exact nearest-rank
+    /// quantile of the histogram; under
+    /// [`HistogramConfig::StreamingQuantiles`] `q` must be one of the
+    /// configured quantiles and the P² estimate is returned.
+    pub fn node_quantile(&self, g: &Graph<'a>, q: f64) -> Option<f64> {
+        let id = *self.address_map.get(&NonNull::from(g))?;
+        match &self.histogram_config {
+            HistogramConfig::Exact | HistogramConfig::Dense { .. } => {
+                let dist = &self.node_info[id];
+                let total: usize = dist.values().sum();
+                if total == 0 {
+                    return None;
+                }
+                let rank = ((q * total as f64).ceil() as usize).clamp(1, total);
+                let mut seen = 0;
+                dist.iter().find_map(|(interval, count)| {
+                    seen += count;
+                    (seen >= rank).then_some(*interval as f64)
+                })
+            }
+            HistogramConfig::StreamingQuantiles { .. } => self.node_quantiles[id]
+                .iter()
+                .find(|x| (x.quantile() - q).abs() < f64::EPSILON)
+                .and_then(P2Quantile::estimate),
+        }
+    }
+}
+
+fn miss_ratio_curve<'b>(
+    nodes: impl Iterator<Item = (&'b BTreeMap<usize, usize>, u64)>,
+    sizes: &[usize],
+) -> Vec<f64> {
+    let mut merged = BTreeMap::new();
+    let mut total = 0;
+    for (dist, count) in nodes {
+        total += count;
+        for (interval, n) in dist {
+            *merged.entry(*interval).or_insert(0u64) += *n as u64;
+        }
+    }
+    if total == 0 {
+        return Vec::new();
+    }
+    let reused: u64 = merged.values().sum();
+    let cold = total - reused;
+    sizes
+        .iter()
+        .map(|size| {
+            let far: u64 = size
+                .checked_add(1)
+                .map_or(0, |x| merged.range(x..).map(|(_, n)| n).sum());
+            (cold + far) as f64 / total as f64
+        })
+        .collect()
 }
 
+/// `SLAP_SIM_INVALID`: the node or memref passed over the FFI is unknown.
+const SIM_INVALID: usize = usize::MAX;
+
 #[no_mangle]
 pub unsafe extern "C" fn slap_sim_access(
     ctx: *const UnsafeCell<SimulationCtx>,
@@ -91,7 +1371,46 @@ pub unsafe extern "C" fn slap_sim_access(
     block_id: usize,
 ) {
     let ctx = &mut *(*ctx).get();
-    ctx.access(node_id, block_id);
+    if node_id < ctx.node_info.len() {
+        ctx.access(node_id, block_id);
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn slap_sim_access_weighted(
+    ctx: *const UnsafeCell<SimulationCtx>,
+    node_id: usize,
+    block_id: usize,
+    weight: usize,
+) {
+    let ctx = &mut *(*ctx).get();
+    if node_id < ctx.node_info.len() {
+        ctx.access_weighted(node_id, block_id, weight);
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn slap_sim_access_address(
+    ctx: *const UnsafeCell<SimulationCtx>,
+    node_id: usize,
+    address: usize,
+) -> bool {
+    let ctx = &mut *(*ctx).get();
+    node_id < ctx.node_info.len() && ctx.access_address(node_id, address)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn slap_sim_get_max_logic_time(
+    ctx: *const UnsafeCell<SimulationCtx>,
+) -> usize {
+    let ctx = &*(*ctx).get();
+    ctx.max_logic_time.unwrap_or(SIM_INVALID)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn slap_sim_report_timeout(ctx: *const UnsafeCell<SimulationCtx>) {
+    let ctx = &mut *(*ctx).get();
+    ctx.timed_out = true;
 }
 
 #[no_mangle]
@@ -100,7 +1419,7 @@ pub unsafe extern "C" fn slap_sim_get_memref_vaddr(
     memref_id: usize,
 ) -> usize {
     let ctx = &mut *(*ctx).get();
-    ctx.vaddrs[memref_id]
+    ctx.vaddrs.get(memref_id).copied().unwrap_or(SIM_INVALID)
 }
 
 #[no_mangle]
@@ -109,7 +1428,9 @@ pub unsafe extern "C" fn slap_sim_get_node_id(
     graph: *mut Graph,
 ) -> usize {
     let ctx = &mut *(*ctx).get();
-    ctx.address_map[&NonNull::new_unchecked(graph)]
+    NonNull::new(graph)
+        .and_then(|x| ctx.address_map.get(&x).copied())
+        .unwrap_or(SIM_INVALID)
 }
 
 #[no_mangle]
@@ -118,8 +1439,402 @@ pub unsafe extern "C" fn slap_sim_get_block_size(ctx: *const UnsafeCell<Simulati
     ctx.block_size
 }
 
+#[no_mangle]
+pub unsafe extern "C" fn slap_sim_get_base_addr(ctx: *const UnsafeCell<SimulationCtx>) -> usize {
+    let ctx = &mut *(*ctx).get();
+    ctx.base_addr
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn slap_sim_get_memref_block_size(
+    ctx: *const UnsafeCell<SimulationCtx>,
+    memref_id: usize,
+) -> usize {
+    let ctx = &mut *(*ctx).get();
+    ctx.get_memref_block_size(memref_id)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn slap_sim_get_node_interval_count(
+    ctx: *const UnsafeCell<SimulationCtx>,
+    node_id: usize,
+    interval: usize,
+) -> usize {
+    let ctx = &*(*ctx).get();
+    ctx.node_info
+        .get(node_id)
+        .and_then(|x| x.get(&interval).copied())
+        .unwrap_or(0)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn slap_sim_node_interval_iter(
+    ctx: *const UnsafeCell<SimulationCtx>,
+    node_id: usize,
+) -> usize {
+    let ctx = &*(*ctx).get();
+    ctx.node_info.get(node_id).map_or(0, BTreeMap::len)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn slap_sim_node_histogram_copy(
+    ctx: *const UnsafeCell<SimulationCtx>,
+    node_id: usize,
+    intervals: *mut usize,
+    counts: *mut usize,
+    capacity: usize,
+) -> usize {
+    let ctx = &*(*ctx).get();
+    let Some(dist) = ctx.node_info.get(node_id) else {
+        return 0;
+    };
+    for (i, (interval, count)) in dist.iter().take(capacity).enumerate() {
+        *intervals.add(i) = *interval;
+        *counts.add(i) = *count;
+    }
+    dist.len()
+}
+
 #[allow(improper_ctypes)]
 extern "C" {
     pub fn slap_initialize_llvm();
     pub fn slap_run_simulation<'a>(ctx: *const UnsafeCell<SimulationCtx>, graph: *const Graph<'a>);
 }
+
+/// Run the native simulation of `graph`, refusing to start if the graph was
+/// rewired since `populate_node_info`.
+pub unsafe fn run_simulation<'a>(
+    ctx: &UnsafeCell<SimulationCtx<'a>>,
+    graph: &'a Graph<'a>,
+) -> Result<(), GraphChanged> {
+    (*ctx.get()).ensure_unchanged(graph)?;
+    slap_run_simulation(ctx, graph);
+    (*ctx.get()).merge_partitions();
+    (*ctx.get()).flush_dense();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        builder::{GraphBuilder, GraphRef, LoopNestBuilder},
+        graph::AccessKind,
+        Context,
+    };
+
+    #[test]
+    fn block_sizes_do_not_alias() {
+        let ctx = Context::with_capacity(4096);
+        let builder = GraphBuilder::new(&ctx);
+        let second = builder.access(
+            1,
+            builder.expr(&[], 0),
+            AccessKind::Read,
+            Some(builder.end()),
+        );
+        let first = builder.access(0, builder.expr(&[], 128), AccessKind::Read, Some(second));
+        let graph = builder.start(Some(first)).get();
+        // Block 16 of both memrefs under `address / block_size`.
+        let vaddrs = [0, 1024];
+        let mut sctx = SimulationCtx::new(&ctx, 8, &vaddrs, DistanceMode::LogicalTime)
+            .unwrap()
+            .with_lru_cache(4)
+            .with_spatial_tracking();
+        sctx.set_memref_block_size(1, 64).unwrap();
+        sctx.populate_node_info(graph);
+        sctx.run(graph).unwrap();
+        let second = second.get();
+        assert!(sctx.get_node_dist(second).unwrap().is_empty());
+        assert_eq!(sctx.get_node_hit_miss(second), Some((0, 1)));
+        assert!(sctx.get_node_spatial_dist(second).unwrap().is_empty());
+        assert!(sctx.get_node_temporal_dist(second).unwrap().is_empty());
+    }
+
+    /// Two passes over two arrays of 4000 eight-byte elements, interleaved.
+    fn two_pass_loop(ctx: &Context) -> &Graph<'_> {
+        let builder = GraphBuilder::new(ctx);
+        LoopNestBuilder::new(ctx)
+            .for_loop(builder.expr(&[], 0), builder.expr(&[], 2), 1)
+            .for_loop(builder.expr(&[], 0), builder.expr(&[], 4000), 1)
+            .load(0, builder.expr(&[0, 8], 0))
+            .load(1, builder.expr(&[0, 8], 0))
+            .build()
+    }
+
+    #[test]
+    fn sampling_stays_close_to_exact() {
+        let ctx = Context::with_capacity(1 << 16);
+        let graph = two_pass_loop(&ctx);
+        let access = graph
+            .iter()
+            .find(|x| matches!(x, Graph::Access { .. }))
+            .unwrap();
+        let vaddrs = [0, 1 << 20];
+        let sizes = [2, 100, 10000, 20000];
+        let mut exact = SimulationCtx::new(&ctx, 64, &vaddrs, DistanceMode::LogicalTime).unwrap();
+        exact.populate_node_info(graph);
+        exact.run(graph).unwrap();
+        let mut sampled = SimulationCtx::new(&ctx, 64, &vaddrs, DistanceMode::LogicalTime)
+            .unwrap()
+            .with_sampling(SamplingConfig { period: 4 });
+        sampled.populate_node_info(graph);
+        sampled.run(graph).unwrap();
+
+        // A sample of a quarter of 1000 blocks per memref: the scaled counts
+        // are within 25% of the exact ones, and miss ratios within 0.02.
+        let exact_dist = exact.get_node_dist(access).unwrap();
+        let sampled_dist = sampled.get_node_dist(access).unwrap();
+        assert_eq!(
+            exact_dist.keys().collect::<Vec<_>>(),
+            sampled_dist.keys().collect::<Vec<_>>()
+        );
+        for (interval, count) in exact_dist {
+            let error = (sampled_dist[interval] as f64 - *count as f64).abs() / *count as f64;
+            assert!(error < 0.25, "interval {interval}: {error}");
+        }
+        let pairs = [
+            (
+                exact.miss_ratio_curve(access, &sizes),
+                sampled.miss_ratio_curve(access, &sizes),
+            ),
+            (
+                exact.aggregate_miss_ratio_curve(&sizes),
+                sampled.aggregate_miss_ratio_curve(&sizes),
+            ),
+        ];
+        for (exact, sampled) in pairs {
+            assert_eq!(exact.len(), sizes.len());
+            for (x, y) in exact.iter().zip(sampled.iter()) {
+                assert!((x - y).abs() < 0.02, "{exact:?} vs {sampled:?}");
+            }
+        }
+        let hit_rate = sampled.predicted_hit_rate(access, 2).unwrap();
+        assert!((exact.predicted_hit_rate(access, 2).unwrap() - hit_rate).abs() < 0.02);
+    }
+
+    #[test]
+    fn temporal_reuse_skips_other_addresses() {
+        let ctx = Context::with_capacity(4096);
+        let builder = GraphBuilder::new(&ctx);
+        let zero = builder.expr(&[], 0);
+        let third = builder.access(0, zero, AccessKind::Read, Some(builder.end()));
+        let second = builder.access(0, builder.expr(&[], 8), AccessKind::Read, Some(third));
+        let first = builder.access(0, zero, AccessKind::Read, Some(second));
+        let graph = builder.start(Some(first)).get();
+        let vaddrs = [0];
+        let mut sctx = SimulationCtx::new(&ctx, 64, &vaddrs, DistanceMode::LogicalTime)
+            .unwrap()
+            .with_spatial_tracking();
+        sctx.populate_node_info(graph);
+        sctx.run(graph).unwrap();
+        let dists = |node: GraphRef| {
+            let temporal = sctx.get_node_temporal_dist(node.get()).unwrap().clone();
+            let spatial = sctx.get_node_spatial_dist(node.get()).unwrap().clone();
+            (temporal, spatial)
+        };
+        // A, A+8, A: the second access reuses the block, the third reuses A.
+        assert_eq!(dists(second), (BTreeMap::new(), BTreeMap::from([(1, 1)])));
+        assert_eq!(dists(third), (BTreeMap::from([(2, 1)]), BTreeMap::new()));
+    }
+
+    #[test]
+    fn reads_and_writes_reuse_apart() {
+        let ctx = Context::with_capacity(4096);
+        let builder = GraphBuilder::new(&ctx);
+        let zero = builder.expr(&[], 0);
+        // write, read, read-write, read of the same block
+        let last = builder.access(0, zero, AccessKind::Read, Some(builder.end()));
+        let both = builder.access(0, zero, AccessKind::ReadWrite, Some(last));
+        let read = builder.access(0, zero, AccessKind::Read, Some(both));
+        let write = builder.access(0, zero, AccessKind::Write, Some(read));
+        let graph = builder.start(Some(write)).get();
+        let vaddrs = [0];
+        let mut sctx = SimulationCtx::new(&ctx, 64, &vaddrs, DistanceMode::LogicalTime)
+            .unwrap()
+            .with_read_write_tracking();
+        sctx.populate_node_info(graph);
+        sctx.run(graph).unwrap();
+        let read_dist = |x: GraphRef| sctx.get_node_read_dist(x.get()).cloned();
+        let write_dist = |x: GraphRef| sctx.get_node_write_dist(x.get()).cloned();
+        assert_eq!(write_dist(write), Some(BTreeMap::new()));
+        assert_eq!(read_dist(write), None);
+        assert_eq!(read_dist(read), Some(BTreeMap::new()));
+        assert_eq!(read_dist(both), Some(BTreeMap::from([(1, 1)])));
+        assert_eq!(write_dist(both), Some(BTreeMap::from([(2, 1)])));
+        assert_eq!(read_dist(last), Some(BTreeMap::from([(1, 1)])));
+        // The combined histogram still sees every reuse of the block.
+        assert_eq!(
+            sctx.get_node_dist(both.get()),
+            Some(&BTreeMap::from([(1, 1)]))
+        );
+    }
+
+    #[test]
+    fn invalid_extra_block_size() {
+        let ctx = Context::with_capacity(4096);
+        let vaddrs = [0];
+        let sctx = SimulationCtx::new(&ctx, 64, &vaddrs, DistanceMode::LogicalTime).unwrap();
+        assert_eq!(
+            sctx.with_block_sizes(&[32, 48]).err(),
+            Some(InvalidBlockSize(48))
+        );
+    }
+
+    #[test]
+    fn miss_ratio_curve_takes_any_size() {
+        let ctx = Context::with_capacity(1 << 16);
+        let graph = two_pass_loop(&ctx);
+        let vaddrs = [0, 1 << 20];
+        let mut sctx = SimulationCtx::new(&ctx, 64, &vaddrs, DistanceMode::LogicalTime).unwrap();
+        sctx.populate_node_info(graph);
+        sctx.run(graph).unwrap();
+        let curve = sctx.aggregate_miss_ratio_curve(&[0, usize::MAX - 1, usize::MAX]);
+        assert_eq!(curve[0], 1.0);
+        // Only the first touch of each of the 1000 blocks misses.
+        assert_eq!(curve[1], 1000.0 / 16000.0);
+        assert_eq!(curve[2], curve[1]);
+    }
+
+    #[test]
+    fn cache_size_inverts_the_curve() {
+        let ctx = Context::with_capacity(1 << 16);
+        let graph = two_pass_loop(&ctx);
+        let vaddrs = [0, 1 << 20];
+        let mut sctx = SimulationCtx::new(&ctx, 64, &vaddrs, DistanceMode::LogicalTime).unwrap();
+        assert_eq!(sctx.cache_size_for_miss_rate(1.0), None);
+        sctx.populate_node_info(graph);
+        sctx.run(graph).unwrap();
+        assert_eq!(sctx.cache_size_for_miss_rate(1.0), Some(0));
+        // The loads of a block are two apart within a pass; a block is
+        // touched again almost a pass later, and 1000 blocks miss cold.
+        assert_eq!(sctx.cache_size_for_miss_rate(0.2), Some(2));
+        let size = sctx.cache_size_for_miss_rate(0.1).unwrap();
+        let curve = sctx.aggregate_miss_ratio_curve(&[size - 1, size]);
+        assert!(curve[0] > 0.1 && curve[1] <= 0.1);
+        assert_eq!(sctx.cache_size_for_miss_rate(0.05), None);
+    }
+
+    #[test]
+    fn top_k_by_traffic_ranks_nodes() {
+        let ctx = Context::with_capacity(1 << 16);
+        let graph = two_pass_loop(&ctx);
+        let vaddrs = [0, 1 << 20];
+        let mut sctx = SimulationCtx::new(&ctx, 64, &vaddrs, DistanceMode::LogicalTime).unwrap();
+        sctx.set_memref_block_size(1, 128).unwrap();
+        sctx.populate_node_info(graph);
+        sctx.run(graph).unwrap();
+        let rows = sctx.top_k_by_traffic(usize::MAX, 2);
+        assert_eq!(rows.len(), 2);
+        assert!(rows.iter().all(|x| x.accesses == 8000));
+        // Twice the block size halves the misses, so the bytes tie.
+        assert_eq!(rows[0].bytes, rows[1].bytes);
+        assert!(rows[0].node_id < rows[1].node_id);
+        let (small, large) = match rows[0].memref {
+            0 => (&rows[0], &rows[1]),
+            _ => (&rows[1], &rows[0]),
+        };
+        assert_eq!(small.misses, 2 * large.misses);
+        assert_eq!(small.bytes, small.misses * 64);
+        let rows = sctx.top_k_by_traffic(1, 0);
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0].misses, 8000);
+        assert!(sctx.top_k_by_traffic(0, 0).is_empty());
+    }
+
+    #[test]
+    fn hierarchy_traffic_counts_fills() {
+        let ctx = Context::with_capacity(1 << 16);
+        let graph = two_pass_loop(&ctx);
+        let vaddrs = [0, 1 << 20];
+        let mut sctx = SimulationCtx::new(&ctx, 64, &vaddrs, DistanceMode::LogicalTime).unwrap();
+        sctx.populate_node_info(graph);
+        let levels = [
+            cache::CacheModel {
+                sets: 1,
+                ways: 8,
+                block_size: 64,
+                policy: cache::ReplacementPolicy::Lru,
+            },
+            cache::CacheModel {
+                sets: 64,
+                ways: 16,
+                block_size: 128,
+                policy: cache::ReplacementPolicy::Lru,
+            },
+        ];
+        let stats = sctx.simulate_hierarchy(graph, &levels).unwrap();
+        // The first level refetches all 1000 blocks in the second pass; the
+        // second holds both arrays and only misses cold.
+        assert_eq!(stats[0].total.misses, 2000);
+        assert_eq!(stats[1].total.misses, 500);
+        assert_eq!(
+            cache::hierarchy_traffic(&levels, &stats),
+            [2000 * 64, 500 * 128]
+        );
+    }
+
+    #[test]
+    fn histogram_copy_fills_the_buffers() {
+        let ctx = Context::with_capacity(1 << 16);
+        let graph = two_pass_loop(&ctx);
+        let vaddrs = [0, 1 << 20];
+        let mut sctx = SimulationCtx::new(&ctx, 64, &vaddrs, DistanceMode::LogicalTime).unwrap();
+        sctx.populate_node_info(graph);
+        sctx.run(graph).unwrap();
+        let expected = sctx.node_info[0].clone();
+        assert!(expected.len() > 1);
+        let cell = UnsafeCell::new(sctx);
+        let mut intervals = vec![0; expected.len() + 1];
+        let mut counts = vec![0; expected.len() + 1];
+        let copy = |node_id, capacity, intervals: &mut [usize], counts: &mut [usize]| unsafe {
+            slap_sim_node_histogram_copy(
+                &cell,
+                node_id,
+                intervals.as_mut_ptr(),
+                counts.as_mut_ptr(),
+                capacity,
+            )
+        };
+        let len = copy(0, intervals.len(), &mut intervals, &mut counts);
+        assert_eq!(len, expected.len());
+        let copied = intervals.iter().copied().zip(counts.iter().copied());
+        assert!(copied.take(len).eq(expected.iter().map(|(x, y)| (*x, *y))));
+        // A short buffer gets the smallest intervals and the full length.
+        intervals.fill(0);
+        assert_eq!(copy(0, 1, &mut intervals, &mut counts), len);
+        assert_eq!(intervals[..2], [*expected.keys().next().unwrap(), 0]);
+        assert_eq!(copy(usize::MAX, 0, &mut [], &mut []), 0);
+    }
+
+    #[test]
+    fn then_only_branches_are_populated() {
+        let ctx = Context::with_capacity(4096);
+        let builder = GraphBuilder::new(&ctx);
+        // `for i0 in 0..4 { load m0[8*i0]; if i0 < 2 { store m1[8*i0] } }`
+        // with no `else` edge on either branch, so the walk ends in the third
+        // iteration after its load.
+        let cond = builder.branch(0, builder.expr(&[], 4), None, None);
+        let step = builder.update(0, builder.expr(&[1], 1), Some(cond));
+        let store = builder.access(1, builder.expr(&[8], 0), AccessKind::Write, Some(step));
+        let guard = builder.branch(0, builder.expr(&[], 2), Some(store), None);
+        let load = builder.access(0, builder.expr(&[8], 0), AccessKind::Read, Some(guard));
+        unsafe { cond.set_then(Some(load)) }.unwrap();
+        let init = builder.update(0, builder.expr(&[], 0), Some(cond));
+        let graph = builder.start(Some(init)).get();
+        let vaddrs = [0, 4096];
+        let mut sctx = SimulationCtx::new(&ctx, 8, &vaddrs, DistanceMode::LogicalTime).unwrap();
+        sctx.populate_node_info(graph);
+        let accesses = graph
+            .iter()
+            .filter(|x| matches!(x, Graph::Access { .. }))
+            .collect::<Vec<_>>();
+        assert_eq!(accesses.len(), 2);
+        for access in accesses {
+            assert!(sctx.address_map.contains_key(&NonNull::from(access)));
+        }
+        assert_eq!(sctx.access_trace(graph, &[]).unwrap().len(), 5);
+        sctx.run(graph).unwrap();
+        assert!(format!("{graph:?}").contains(", None)"));
+    }
+}
@@ -0,0 +1,113 @@
+use std::collections::BTreeMap;
+
+use rustc_hash::FxHashMap;
+
+use super::SimulationCtx;
+
+/// A deferred access, stamped with the logic time it happened at.
+#[derive(Debug, Clone, Copy)]
+pub(super) struct Event {
+    pub node_id: usize,
+    pub block_id: usize,
+    pub time: usize,
+    pub weight: usize,
+}
+
+/// Add the counts of `from` into `into`, interval by interval.
+pub fn merge_histograms(into: &mut BTreeMap<usize, usize>, from: &BTreeMap<usize, usize>) {
+    for (interval, count) in from {
+        *into.entry(*interval).or_insert(0) += count;
+    }
+}
+
+/// Per-node logic-time reuse histograms of one time-ordered event stream.
+#[derive(Default)]
+struct Analysis {
+    histograms: FxHashMap<usize, BTreeMap<usize, usize>>,
+    cold_misses: FxHashMap<usize, u64>,
+}
+
+fn analyse(events: &[Event], access_time: &mut FxHashMap<usize, usize>) -> Analysis {
+    let mut result = Analysis::default();
+    for event in events {
+        let last_access = access_time.entry(event.block_id).or_insert(event.time);
+        if *last_access == event.time {
+            *result.cold_misses.entry(event.node_id).or_insert(0) += event.weight as u64;
+        } else {
+            let interval = event.time - *last_access;
+            *result
+                .histograms
+                .entry(event.node_id)
+                .or_default()
+                .entry(interval)
+                .or_insert(0) += event.weight;
+            *last_access = event.time;
+        }
+    }
+    result
+}
+
+impl SimulationCtx<'_> {
+    /// Analyse the accesses buffered by `with_parallel_memrefs`, one thread
+    /// per memref, and merge the results into the node histograms.
+    ///
+    /// Partitions are independent only if no block is shared between
+    /// memrefs; if their block ranges overlap, or earlier accesses are still
+    /// being tracked, the events are replayed serially in time order instead.
+    pub fn merge_partitions(&mut self) {
+        let Some(partitions) = self.partitions.as_mut() else {
+            return;
+        };
+        let partitions = std::mem::take(partitions);
+        if partitions.is_empty() {
+            return;
+        }
+        let mut ranges = partitions
+            .values()
+            .filter_map(|events| {
+                let min = events.iter().map(|x| x.block_id).min()?;
+                let max = events.iter().map(|x| x.block_id).max()?;
+                Some((min, max))
+            })
+            .collect::<Vec<_>>();
+        ranges.sort_unstable();
+        let aliased = ranges.windows(2).any(|x| x[1].0 <= x[0].1);
+
+        let results = if aliased || !self.access_time.is_empty() {
+            let mut events = partitions.into_values().flatten().collect::<Vec<_>>();
+            events.sort_unstable_by_key(|x| x.time);
+            vec![analyse(&events, &mut self.access_time)]
+        } else {
+            let results = std::thread::scope(|scope| {
+                partitions
+                    .into_values()
+                    .map(|events| {
+                        scope.spawn(move || {
+                            let mut access_time = FxHashMap::default();
+                            let result = analyse(&events, &mut access_time);
+                            (result, access_time)
+                        })
+                    })
+                    .collect::<Vec<_>>()
+                    .into_iter()
+                    .map(|x| x.join().unwrap())
+                    .collect::<Vec<_>>()
+            });
+            results
+                .into_iter()
+                .map(|(result, access_time)| {
+                    self.access_time.extend(access_time);
+                    result
+                })
+                .collect()
+        };
+        for result in results {
+            for (node_id, dist) in result.histograms {
+                merge_histograms(&mut self.node_info[node_id], &dist);
+            }
+            for (node_id, count) in result.cold_misses {
+                self.cold_misses[node_id] += count;
+            }
+        }
+    }
+}
@@ -0,0 +1,53 @@
+use std::{path::Path, sync::Arc};
+
+use arrow_array::{RecordBatch, UInt64Array};
+use arrow_schema::{DataType, Field, Schema};
+use parquet::{
+    arrow::ArrowWriter, basic::Compression, errors::ParquetError,
+    file::properties::WriterProperties,
+};
+
+use super::SimulationCtx;
+
+impl SimulationCtx<'_> {
+    /// Write every node's reuse histogram as a Parquet table with columns
+    /// `node_id`, `memref`, `interval` and `count`, ordered by node id and
+    /// then interval.
+    pub fn write_histograms_parquet(&self, path: &Path) -> Result<(), ParquetError> {
+        let mut node_id = Vec::new();
+        let mut memref = Vec::new();
+        let mut interval = Vec::new();
+        let mut count = Vec::new();
+        for (id, dist) in self.node_info.iter().enumerate() {
+            for (k, v) in dist {
+                node_id.push(id as u64);
+                memref.push(self.node_memref[id] as u64);
+                interval.push(*k as u64);
+                count.push(*v as u64);
+            }
+        }
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("node_id", DataType::UInt64, false),
+            Field::new("memref", DataType::UInt64, false),
+            Field::new("interval", DataType::UInt64, false),
+            Field::new("count", DataType::UInt64, false),
+        ]));
+        let batch = RecordBatch::try_new(
+            schema.clone(),
+            vec![
+                Arc::new(UInt64Array::from(node_id)),
+                Arc::new(UInt64Array::from(memref)),
+                Arc::new(UInt64Array::from(interval)),
+                Arc::new(UInt64Array::from(count)),
+            ],
+        )?;
+        let props = WriterProperties::builder()
+            .set_compression(Compression::SNAPPY)
+            .build();
+        let file = std::fs::File::create(path)?;
+        let mut writer = ArrowWriter::try_new(file, schema, Some(props))?;
+        writer.write(&batch)?;
+        writer.close()?;
+        Ok(())
+    }
+}
@@ -21,20 +21,30 @@
 namespace {
 
 llvm::FunctionType *getExternalAccessType(llvm::LLVMContext &ctx) {
-  /* void slap_sim_access(slap_sim_context_t, size_t node_id,  size_t
-   * block_id);*/
+  /* bool slap_sim_access_address(slap_sim_context_t, size_t node_id,
+   * size_t address);*/
   auto void_type = llvm::Type::getVoidTy(ctx);
+  auto bool_ty = llvm::Type::getInt8Ty(ctx);
   auto size_t_ty = llvm::Type::getInt64Ty(ctx);
   auto ptr_ty = void_type->getPointerTo();
-  return llvm::FunctionType::get(void_type, {ptr_ty, size_t_ty, size_t_ty},
+  return llvm::FunctionType::get(bool_ty, {ptr_ty, size_t_ty, size_t_ty},
                                  false);
 }
 
+llvm::FunctionType *getExternalTimeoutType(llvm::LLVMContext &ctx) {
+  /* void slap_sim_report_timeout(slap_sim_context_t); */
+  auto void_type = llvm::Type::getVoidTy(ctx);
+  auto ptr_ty = void_type->getPointerTo();
+  return llvm::FunctionType::get(void_type, {ptr_ty}, false);
+}
+
 llvm::FunctionType *getFunctionType(llvm::LLVMContext &ctx) {
   auto void_type = llvm::Type::getVoidTy(ctx);
   auto ptr_ty = void_type->getPointerTo();
   auto func_ptr_ty = getExternalAccessType(ctx)->getPointerTo();
-  return llvm::FunctionType::get(void_type, {ptr_ty, func_ptr_ty}, false);
+  auto timeout_ptr_ty = getExternalTimeoutType(ctx)->getPointerTo();
+  return llvm::FunctionType::get(void_type,
+                                 {ptr_ty, func_ptr_ty, timeout_ptr_ty}, false);
 }
 
 class CodegenContext {
@@ -45,6 +55,11 @@ class CodegenContext {
   llvm::DenseMap<slap_graph_t, llvm::BasicBlock *> state_map;
   llvm::DenseMap<size_t, llvm::AllocaInst *> ivar_map;
   llvm::BasicBlock *entry;
+  llvm::BasicBlock *exit = nullptr;
+  llvm::BasicBlock *timeout = nullptr;
+  llvm::BasicBlock *stall = nullptr;
+  llvm::AllocaInst *idle = nullptr;
+  size_t max_logic_time;
   slap_sim_context_t sim_ctx;
 
 public:
@@ -54,7 +69,9 @@ public:
         func(llvm::Function::Create(getFunctionType(*ctx),
                                     llvm::Function::ExternalLinkage,
                                     "simulation_entrypoint", *module)),
-        builder(*ctx), state_map(), sim_ctx(sim_ctx) {}
+        builder(*ctx), state_map(),
+        max_logic_time(slap_sim_get_max_logic_time(sim_ctx)), sim_ctx(sim_ctx) {
+  }
 
 private:
   llvm::BasicBlock *getBasicBlock(slap_graph_t node) {
@@ -69,6 +86,7 @@ private:
     auto it = func->arg_begin();
     return ++it;
   }
+  llvm::Value *getExternalTimeoutArg() { return func->arg_begin() + 2; }
 
   llvm::BasicBlock *newBasicBlock(slap_graph_t node) {
     std::string name =
@@ -78,6 +96,61 @@ private:
     return bb;
   }
 
+  // Shared exit for null edges, which end the walk like an End node.
+  llvm::BasicBlock *getExitBlock() {
+    if (!exit) {
+      auto currentInsertPoint = builder.saveIP();
+      exit = llvm::BasicBlock::Create(*ctx, "exit", func);
+      builder.SetInsertPoint(exit);
+      builder.CreateRetVoid();
+      builder.restoreIP(currentInsertPoint);
+    }
+    return exit;
+  }
+
+  // Shared exit taken when an access reports that the logic-time limit has
+  // been reached.
+  llvm::BasicBlock *getTimeoutBlock() {
+    if (!timeout) {
+      auto currentInsertPoint = builder.saveIP();
+      timeout = llvm::BasicBlock::Create(*ctx, "timeout", func);
+      builder.SetInsertPoint(timeout);
+      builder.CreateRetVoid();
+      builder.restoreIP(currentInsertPoint);
+    }
+    return timeout;
+  }
+
+  // Exit taken when too many branches ran in a row without an access, which
+  // an access-free cycle would otherwise do forever.
+  llvm::BasicBlock *getStallBlock() {
+    if (!stall) {
+      auto currentInsertPoint = builder.saveIP();
+      stall = llvm::BasicBlock::Create(*ctx, "stall", func);
+      builder.SetInsertPoint(stall);
+      builder.CreateCall(getExternalTimeoutType(*ctx), getExternalTimeoutArg(),
+                         {getCtxArg()});
+      builder.CreateRetVoid();
+      builder.restoreIP(currentInsertPoint);
+    }
+    return stall;
+  }
+
+  // Number of branches run since the last access, or nullptr without a
+  // limit.
+  llvm::AllocaInst *getIdleAlloca() {
+    if (max_logic_time == SLAP_SIM_INVALID)
+      return nullptr;
+    if (!idle) {
+      auto currentInsertPoint = builder.saveIP();
+      builder.SetInsertPoint(entry);
+      idle = builder.CreateAlloca(llvm::Type::getInt64Ty(*ctx));
+      builder.CreateStore(builder.getInt64(0), idle);
+      builder.restoreIP(currentInsertPoint);
+    }
+    return idle;
+  }
+
   llvm::AllocaInst *getIVarAlloca(size_t ivar) {
     auto it = ivar_map.find(ivar);
     if (it == ivar_map.end()) {
@@ -108,10 +181,30 @@ private:
     }
     if (bias != 0)
       acc = builder.CreateAdd(acc, builder.getInt64(bias));
+    for (size_t term = 0; term < slap_expr_get_term_count(expr); ++term) {
+      auto operand = emitExpr(slap_expr_get_term_operand(expr, term));
+      auto divisor = builder.getInt64(slap_expr_get_term_divisor(expr, term));
+      // sdiv/srem truncate; shift negative remainders to floor instead.
+      auto quot = builder.CreateSDiv(operand, divisor);
+      auto rem = builder.CreateSRem(operand, divisor);
+      auto negative = builder.CreateICmpSLT(rem, builder.getInt64(0));
+      llvm::Value *value;
+      if (slap_expr_get_term_op(expr, term) == SLAP_QUASI_FLOOR_DIV)
+        value = builder.CreateSelect(
+            negative, builder.CreateSub(quot, builder.getInt64(1)), quot);
+      else
+        value = builder.CreateSelect(negative, builder.CreateAdd(rem, divisor),
+                                     rem);
+      auto coeff = slap_expr_get_term_coefficient(expr, term);
+      acc = builder.CreateAdd(acc,
+                              builder.CreateMul(value, builder.getInt64(coeff)));
+    }
     return acc;
   }
 
   llvm::BasicBlock *emitSimulation(slap_graph_t node) {
+    if (!node)
+      return this->getExitBlock();
     if (auto *bb = this->getBasicBlock(node))
       return bb;
 
@@ -137,19 +230,26 @@ private:
       auto offset = this->emitExpr(expr);
       auto memref = slap_graph_get_identifer(node);
       auto vaddr = slap_sim_get_memref_vaddr(sim_ctx, memref);
-      auto block_sz = slap_sim_get_block_size(sim_ctx);
+      if (vaddr == SLAP_SIM_INVALID)
+        llvm::report_fatal_error("memref has no base address");
       auto node_id = slap_sim_get_node_id(sim_ctx, node);
-      auto block_id = this->builder.CreateUDiv(
-          this->builder.CreateAdd(offset, this->builder.getInt64(vaddr)),
-          this->builder.getInt64(block_sz));
+      if (node_id == SLAP_SIM_INVALID)
+        llvm::report_fatal_error("access node was not registered");
+      auto address =
+          this->builder.CreateAdd(offset, this->builder.getInt64(vaddr));
       auto access = this->getExternalAccessArg();
-      this->builder.CreateCall(
+      auto proceed = this->builder.CreateCall(
           getExternalAccessType(*ctx), access,
-          {getCtxArg(), this->builder.getInt64(node_id), block_id});
+          {getCtxArg(), this->builder.getInt64(node_id), address});
+      auto cond =
+          this->builder.CreateICmpNE(proceed, this->builder.getInt8(0));
+      if (auto idle = this->getIdleAlloca())
+        this->builder.CreateStore(this->builder.getInt64(0), idle);
       auto next = slap_graph_get_next(node);
       auto next_bb = this->emitSimulation(next);
+      auto timeout_bb = this->getTimeoutBlock();
       this->builder.SetInsertPoint(bb);
-      this->builder.CreateBr(next_bb);
+      this->builder.CreateCondBr(cond, next_bb, timeout_bb);
       break;
     }
     case SLAP_GRAPH_UPDATE: {
@@ -167,6 +267,18 @@ private:
     }
     case SLAP_GRAPH_BRANCH: {
       this->builder.SetInsertPoint(bb);
+      if (auto idle = this->getIdleAlloca()) {
+        auto int64_ty = this->builder.getInt64Ty();
+        auto count = this->builder.CreateAdd(
+            this->builder.CreateLoad(int64_ty, idle), this->builder.getInt64(1));
+        this->builder.CreateStore(count, idle);
+        auto stalled = this->builder.CreateICmpUGT(
+            count, this->builder.getInt64(max_logic_time));
+        auto test = llvm::BasicBlock::Create(*ctx, "test", func);
+        this->builder.CreateCondBr(stalled, this->getStallBlock(), test);
+        this->builder.SetInsertPoint(test);
+      }
+      auto test = this->builder.GetInsertBlock();
       auto expr = slap_graph_get_expr(node);
       auto bound = this->emitExpr(expr);
       auto ivar = slap_graph_get_identifer(node);
@@ -175,7 +287,7 @@ private:
       auto cmp = this->builder.CreateICmpSLT(load, bound);
       auto then_ = this->emitSimulation(slap_graph_get_then(node));
       auto else_ = this->emitSimulation(slap_graph_get_else(node));
-      this->builder.SetInsertPoint(bb);
+      this->builder.SetInsertPoint(test);
       this->builder.CreateCondBr(cmp, then_, else_);
       break;
     }
@@ -228,8 +340,9 @@ public:
     auto symbol = jit->get()->lookup("simulation_entrypoint");
     if (!symbol)
       llvm::report_fatal_error("Failed to find symbol");
-    auto function = symbol.get().toPtr<void (*)(void *, void *)>();
-    function(sim_ctx, reinterpret_cast<void *>(slap_sim_access));
+    auto function = symbol.get().toPtr<void (*)(void *, void *, void *)>();
+    function(sim_ctx, reinterpret_cast<void *>(slap_sim_access_address),
+             reinterpret_cast<void *>(slap_sim_report_timeout));
   }
 };
 
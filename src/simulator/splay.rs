@@ -0,0 +1,248 @@
+use rustc_hash::FxHashMap;
+
+const NIL: usize = usize::MAX;
+
+#[derive(Debug, Clone, Copy)]
+struct Node {
+    left: usize,
+    right: usize,
+    parent: usize,
+    size: usize,
+}
+
+/// LRU stack distance tracker backed by an order-statistics splay tree.
+///
+/// The tree holds one node per block, ordered by last access time, so the
+/// stack distance of a block is one plus the number of nodes to its right.
+/// A new access is always the most recent, so it becomes the root with the
+/// previous tree as its left child. Because the block being looked up is
+/// splayed to the root, re-touching one of the most recently used blocks
+/// only walks a few nodes near the right spine, which makes the common
+/// high-locality case close to O(1) on top of the O(log n) amortized bound.
+#[derive(Debug, Default)]
+pub struct StackDistance {
+    nodes: Vec<Node>,
+    free: Vec<usize>,
+    root: Option<usize>,
+    position: FxHashMap<usize, usize>,
+}
+
+impl StackDistance {
+    /// Record an access to `block`, returning its stack distance: the number
+    /// of distinct blocks (itself included) touched since its previous
+    /// access, or `None` on the first touch.
+    pub fn access(&mut self, block: usize) -> Option<usize> {
+        let distance = self.position.get(&block).copied().map(|x| {
+            self.splay(x);
+            let distance = self.size(self.nodes[x].right) + 1;
+            self.remove_root();
+            self.free.push(x);
+            distance
+        });
+        let x = self.push_max();
+        self.position.insert(block, x);
+        distance
+    }
+
+    pub fn clear(&mut self) {
+        self.nodes.clear();
+        self.free.clear();
+        self.root = None;
+        self.position.clear();
+    }
+
+    fn size(&self, x: usize) -> usize {
+        if x == NIL {
+            0
+        } else {
+            self.nodes[x].size
+        }
+    }
+
+    fn update(&mut self, x: usize) {
+        self.nodes[x].size = 1 + self.size(self.nodes[x].left) + self.size(self.nodes[x].right);
+    }
+
+    fn set_parent(&mut self, x: usize, parent: usize) {
+        if x != NIL {
+            self.nodes[x].parent = parent;
+        }
+    }
+
+    fn push_max(&mut self) -> usize {
+        let left = self.root.unwrap_or(NIL);
+        let node = Node {
+            left,
+            right: NIL,
+            parent: NIL,
+            size: 1 + self.size(left),
+        };
+        let x = match self.free.pop() {
+            Some(x) => {
+                self.nodes[x] = node;
+                x
+            }
+            None => {
+                self.nodes.push(node);
+                self.nodes.len() - 1
+            }
+        };
+        self.set_parent(left, x);
+        self.root = Some(x);
+        x
+    }
+
+    fn rotate(&mut self, x: usize) {
+        let p = self.nodes[x].parent;
+        let g = self.nodes[p].parent;
+        if self.nodes[p].left == x {
+            let b = self.nodes[x].right;
+            self.nodes[p].left = b;
+            self.set_parent(b, p);
+            self.nodes[x].right = p;
+        } else {
+            let b = self.nodes[x].left;
+            self.nodes[p].right = b;
+            self.set_parent(b, p);
+            self.nodes[x].left = p;
+        }
+        self.nodes[p].parent = x;
+        self.nodes[x].parent = g;
+        if g != NIL {
+            if self.nodes[g].left == p {
+                self.nodes[g].left = x;
+            } else {
+                self.nodes[g].right = x;
+            }
+        }
+        self.update(p);
+        self.update(x);
+    }
+
+    fn splay(&mut self, x: usize) {
+        while self.nodes[x].parent != NIL {
+            let p = self.nodes[x].parent;
+            let g = self.nodes[p].parent;
+            if g != NIL {
+                let zig_zig = (self.nodes[g].left == p) == (self.nodes[p].left == x);
+                self.rotate(if zig_zig { p } else { x });
+            }
+            self.rotate(x);
+        }
+        self.root = Some(x);
+    }
+
+    fn remove_root(&mut self) {
+        let Some(x) = self.root else {
+            return;
+        };
+        let Node { left, right, .. } = self.nodes[x];
+        self.set_parent(left, NIL);
+        self.set_parent(right, NIL);
+        if left == NIL {
+            self.root = (right != NIL).then_some(right);
+            return;
+        }
+        let mut max = left;
+        while self.nodes[max].right != NIL {
+            max = self.nodes[max].right;
+        }
+        self.splay(max);
+        self.nodes[max].right = right;
+        self.set_parent(right, max);
+        self.update(max);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+
+    /// Deterministic trace over `blocks` blocks: mostly a hot eighth of the
+    /// blocks, with uniform accesses to all of them mixed in.
+    fn trace(len: usize, blocks: u64) -> Vec<usize> {
+        let mut state = 0x2545_f491_4f6c_dd1d_u64;
+        (0..len)
+            .map(|_| {
+                state = state
+                    .wrapping_mul(6364136223846793005)
+                    .wrapping_add(1442695040888963407);
+                let x = state >> 33;
+                match x % 4 {
+                    0 => (x / 4 % blocks) as usize,
+                    _ => (x / 4 % (blocks / 8).max(1)) as usize,
+                }
+            })
+            .collect()
+    }
+
+    /// Stack distance by searching an explicit LRU stack.
+    fn naive(trace: &[usize]) -> Vec<Option<usize>> {
+        let mut stack = Vec::<usize>::new();
+        trace
+            .iter()
+            .map(|block| {
+                let distance = stack
+                    .iter()
+                    .rposition(|x| x == block)
+                    .map(|x| stack.len() - x);
+                if let Some(distance) = distance {
+                    stack.remove(stack.len() - distance);
+                }
+                stack.push(*block);
+                distance
+            })
+            .collect()
+    }
+
+    /// Stack distance by counting the blocks touched since the last access
+    /// in a `BTreeMap` keyed by access time.
+    #[derive(Default)]
+    struct BTreeStack {
+        last: FxHashMap<usize, usize>,
+        order: BTreeMap<usize, usize>,
+        time: usize,
+    }
+
+    impl BTreeStack {
+        fn access(&mut self, block: usize) -> Option<usize> {
+            let distance = self.last.insert(block, self.time).map(|last| {
+                let distance = self.order.range(last..).count();
+                self.order.remove(&last);
+                distance
+            });
+            self.order.insert(self.time, block);
+            self.time += 1;
+            distance
+        }
+    }
+
+    #[test]
+    fn matches_naive_lru_stack() {
+        let trace = trace(5000, 400);
+        let mut splay = StackDistance::default();
+        let distances = trace.iter().map(|x| splay.access(*x)).collect::<Vec<_>>();
+        assert_eq!(distances, naive(&trace));
+        splay.clear();
+        assert_eq!(splay.access(trace[0]), None);
+    }
+
+    /// `cargo test --release -- --ignored --nocapture` prints the timings.
+    #[test]
+    #[ignore]
+    fn bench_against_btree() {
+        let trace = trace(200_000, 20_000);
+        let start = std::time::Instant::now();
+        let mut splay = StackDistance::default();
+        let expected = trace.iter().map(|x| splay.access(*x)).collect::<Vec<_>>();
+        let splay_time = start.elapsed();
+        let start = std::time::Instant::now();
+        let mut btree = BTreeStack::default();
+        let distances = trace.iter().map(|x| btree.access(*x)).collect::<Vec<_>>();
+        let btree_time = start.elapsed();
+        assert_eq!(distances, expected);
+        println!("splay: {splay_time:?}, btree: {btree_time:?}");
+    }
+}
@@ -0,0 +1,148 @@
+use std::{ops::ControlFlow, ptr::NonNull};
+
+use rustc_hash::FxHashSet;
+
+use crate::{
+    affine::Expr,
+    graph::{AccessKind, Graph},
+};
+
+/// What [`Graph::walk`] does after a visitor callback.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Walk {
+    /// Go on into the successors of the node.
+    Continue,
+    /// Do not enter the successors from this node. They are still visited if
+    /// the walk reaches them some other way.
+    SkipChildren,
+    /// Stop the walk.
+    Break,
+}
+
+/// Callbacks for [`Graph::walk`], one per node kind. Every method defaults
+/// to doing nothing and returning [`Walk::Continue`].
+pub trait GraphVisitor<'a> {
+    fn visit_start(&mut self, _node: &'a Graph<'a>) -> Walk {
+        Walk::Continue
+    }
+    fn visit_end(&mut self, _node: &'a Graph<'a>) -> Walk {
+        Walk::Continue
+    }
+    fn visit_access(
+        &mut self,
+        _node: &'a Graph<'a>,
+        _memref: usize,
+        _offset: &'a Expr<'a>,
+        _kind: AccessKind,
+    ) -> Walk {
+        Walk::Continue
+    }
+    fn visit_update(&mut self, _node: &'a Graph<'a>, _ivar: usize, _expr: &'a Expr<'a>) -> Walk {
+        Walk::Continue
+    }
+    fn visit_branch(&mut self, _node: &'a Graph<'a>, _ivar: usize, _bound: &'a Expr<'a>) -> Walk {
+        Walk::Continue
+    }
+}
+
+impl<'a> Graph<'a> {
+    /// Visit every reachable node once, in the preorder of [`Graph::iter`],
+    /// until the visitor breaks. Returns `ControlFlow::Break` if it did.
+    pub fn walk<V: GraphVisitor<'a>>(&'a self, visitor: &mut V) -> ControlFlow<()> {
+        let mut stack = vec![self];
+        let mut visited = FxHashSet::default();
+        while let Some(node) = stack.pop() {
+            if !visited.insert(NonNull::from(node)) {
+                continue;
+            }
+            let action = match *node {
+                Graph::Start(_) => visitor.visit_start(node),
+                Graph::End => visitor.visit_end(node),
+                Graph::Access {
+                    memref,
+                    offset,
+                    kind,
+                    ..
+                } => visitor.visit_access(node, memref, offset, kind),
+                Graph::Update { ivar, expr, .. } => visitor.visit_update(node, ivar, expr),
+                Graph::Branch { ivar, bound, .. } => visitor.visit_branch(node, ivar, bound),
+            };
+            match action {
+                Walk::Continue => {
+                    let (first, second) = node.edges();
+                    stack.extend(second);
+                    stack.extend(first);
+                }
+                Walk::SkipChildren => (),
+                Walk::Break => return ControlFlow::Break(()),
+            }
+        }
+        ControlFlow::Continue(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{builder::GraphBuilder, Context};
+
+    /// Collects the memrefs of the accesses it visits, skipping whatever
+    /// follows a branch if `skip_branches` is set.
+    struct Memrefs {
+        skip_branches: bool,
+        seen: Vec<usize>,
+    }
+
+    impl<'a> GraphVisitor<'a> for Memrefs {
+        fn visit_access(
+            &mut self,
+            _node: &'a Graph<'a>,
+            memref: usize,
+            _offset: &'a Expr<'a>,
+            _kind: AccessKind,
+        ) -> Walk {
+            self.seen.push(memref);
+            Walk::Continue
+        }
+        fn visit_branch(
+            &mut self,
+            _node: &'a Graph<'a>,
+            _ivar: usize,
+            _bound: &'a Expr<'a>,
+        ) -> Walk {
+            if self.skip_branches {
+                Walk::SkipChildren
+            } else {
+                Walk::Continue
+            }
+        }
+    }
+
+    #[test]
+    fn skip_children_of_loop() {
+        let ctx = Context::with_capacity(4096);
+        let builder = GraphBuilder::new(&ctx);
+        let zero = builder.expr(&[], 0);
+        let after = builder.access(2, zero, AccessKind::Read, Some(builder.end()));
+        let cond = builder.branch(0, builder.expr(&[], 4), None, Some(after));
+        let step = builder.update(0, builder.expr(&[1], 1), Some(cond));
+        let body = builder.access(1, zero, AccessKind::Read, Some(step));
+        unsafe { cond.set_then(Some(body)) }.unwrap();
+        let init = builder.update(0, zero, Some(cond));
+        let before = builder.access(0, zero, AccessKind::Read, Some(init));
+        let graph = builder.start(Some(before)).get();
+
+        let mut all = Memrefs {
+            skip_branches: false,
+            seen: Vec::new(),
+        };
+        assert!(graph.walk(&mut all).is_continue());
+        assert_eq!(all.seen, [0, 1, 2]);
+        let mut skipped = Memrefs {
+            skip_branches: true,
+            seen: Vec::new(),
+        };
+        assert!(graph.walk(&mut skipped).is_continue());
+        assert_eq!(skipped.seen, [0]);
+    }
+}